@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::{Json, WithStatus};
+
+use crate::Server;
+use crate::sessions::Sessions;
+use crate::math::vector3::Vector3;
+
+type ApiReply = WithStatus<Json>;
+
+fn reply(status: StatusCode, body: &Value) -> ApiReply {
+  warp::reply::with_status(warp::reply::json(body), status)
+}
+
+fn error(status: StatusCode, message: &str) -> ApiReply {
+  reply(status, &json!({ "error": message }))
+}
+
+fn stopped() -> ApiReply {
+  error(StatusCode::SERVICE_UNAVAILABLE, "the simulation has stopped")
+}
+
+fn with_server(server: Server) -> impl Filter<Extract = (Server,), Error = Infallible> + Clone {
+  warp::any().map(move || server.clone())
+}
+
+// JSON over http for scripts, running the same commands as the websocket
+pub fn routes(server: Server, sessions: Sessions) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let body = warp::body::content_length_limit(1 << 20).and(warp::body::json());
+  let list_entities = warp::path!("api" / "entities")
+    .and(warp::get())
+    .and(with_server(server.clone()))
+    .and_then(list_entities);
+  let get_entity = warp::path!("api" / "entities" / i32)
+    .and(warp::get())
+    .and(with_server(server.clone()))
+    .and_then(get_entity);
+  let create_entity = warp::path!("api" / "entities")
+    .and(warp::post())
+    .and(body)
+    .and(with_server(server.clone()))
+    .and_then(create_entity);
+  let delete_entity = warp::path!("api" / "entities" / i32)
+    .and(warp::delete())
+    .and(with_server(server.clone()))
+    .and_then(delete_entity);
+  let list_trips = warp::path!("api" / "trips")
+    .and(warp::get())
+    .and(with_server(server.clone()))
+    .and_then(list_trips);
+  let schedule_trip = warp::path!("api" / "trips")
+    .and(warp::post())
+    .and(body)
+    .and(with_server(server.clone()))
+    .and_then(schedule_trip);
+  let find_path = warp::path!("api" / "graph" / "path")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_server(server))
+    .and_then(find_path);
+  let post_command = warp::path!("post" / String)
+    .and(warp::post())
+    .and(warp::body::content_length_limit(1 << 20))
+    .and(warp::body::bytes())
+    .and(warp::any().map(move || sessions.clone()))
+    .and_then(post_command);
+  list_entities
+    .or(get_entity)
+    .or(create_entity)
+    .or(delete_entity)
+    .or(list_trips)
+    .or(schedule_trip)
+    .or(find_path)
+    .or(post_command)
+}
+
+// Runs a command, giving its result or the reply saying why there is none.
+async fn execute(server: Server, name: &str, mut data: Value, failure: StatusCode) -> Result<Value, ApiReply> {
+  if !data.is_object() {
+    return Err(error(StatusCode::BAD_REQUEST, "expected a json object"));
+  }
+  data["command"] = json!(name);
+  let result = server.call(|server| if server.is_replaying() { Err(()) } else { Ok(server.execute(data)) }).await;
+  match result {
+    Some(Ok(Some(result))) => Ok(result),
+    Some(Ok(None)) => Err(error(failure, &format!("{} failed", name))),
+    Some(Err(_)) => Err(error(StatusCode::CONFLICT, "the server is replaying an event log")),
+    None => Err(stopped())
+  }
+}
+
+async fn command(server: Server, name: &str, data: Value, success: StatusCode, failure: StatusCode) -> Result<ApiReply, Rejection> {
+  Ok(match execute(server, name, data, failure).await {
+    Ok(result) => reply(success, &result),
+    Err(reply) => reply
+  })
+}
+
+async fn list_entities(server: Server) -> Result<ApiReply, Rejection> {
+  Ok(match server.call(|server| server.get_entities()).await {
+    Some(entities) => reply(StatusCode::OK, &json!(entities)),
+    None => stopped()
+  })
+}
+
+async fn get_entity(id: i32, server: Server) -> Result<ApiReply, Rejection> {
+  Ok(match server.call(move |server| server.get_entity(id)).await {
+    Some(Some(entity)) => reply(StatusCode::OK, &entity),
+    Some(None) => error(StatusCode::NOT_FOUND, "no such entity"),
+    None => stopped()
+  })
+}
+
+async fn create_entity(data: Value, server: Server) -> Result<ApiReply, Rejection> {
+  command(server, "CreateEntity", data, StatusCode::CREATED, StatusCode::BAD_REQUEST).await
+}
+
+async fn delete_entity(id: i32, server: Server) -> Result<ApiReply, Rejection> {
+  command(server, "RemoveEntity", json!({ "id": id }), StatusCode::OK, StatusCode::NOT_FOUND).await
+}
+
+async fn list_trips(server: Server) -> Result<ApiReply, Rejection> {
+  Ok(match server.call(|server| server.get_trips()).await {
+    Some(trips) => reply(StatusCode::OK, &json!(trips)),
+    None => stopped()
+  })
+}
+
+// every available robot with the given name gets a trip, none is a 404
+async fn schedule_trip(data: Value, server: Server) -> Result<ApiReply, Rejection> {
+  Ok(match execute(server, "ScheduleTrip", data, StatusCode::BAD_REQUEST).await {
+    Ok(result) if result["trip_ids"].as_array().is_some_and(|ids| ids.is_empty()) => {
+      error(StatusCode::NOT_FOUND, "no available robot with that name")
+    },
+    Ok(result) => reply(StatusCode::CREATED, &result),
+    Err(reply) => reply
+  })
+}
+
+// points are given as `x,y,z`
+fn parse_point(text: Option<&String>) -> Option<Vector3> {
+  let p = text?.split(',').map(|v| v.trim().parse::<f64>().ok()).collect::<Option<Vec<f64>>>()?;
+  if p.len() != 3 { return None; }
+  Some(Vector3::from_vec(&p))
+}
+
+async fn find_path(query: HashMap<String, String>, server: Server) -> Result<ApiReply, Rejection> {
+  let (from, to) = match (parse_point(query.get("from")), parse_point(query.get("to"))) {
+    (Some(from), Some(to)) => (from, to),
+    _ => return Ok(error(StatusCode::BAD_REQUEST, "from and to must be given as x,y,z"))
+  };
+  let search = query.get("search").cloned().unwrap_or("astar".to_string());
+  Ok(match server.call(move |server| server.find_path(from, to, &search)).await {
+    Some(Some(path)) => reply(StatusCode::OK, &path),
+    Some(None) => error(StatusCode::NOT_FOUND, "no path found"),
+    None => stopped()
+  })
+}
+
+// A command sent over http on behalf of a websocket client, handled as if
+// that client had sent it. The body is read as json whatever its type.
+async fn post_command(client_id: String, body: Bytes, sessions: Sessions) -> Result<ApiReply, Rejection> {
+  let data = match serde_json::from_slice::<Value>(&body) {
+    Ok(data) if data.is_object() => data,
+    _ => return Ok(error(StatusCode::BAD_REQUEST, "expected a json object"))
+  };
+  Ok(match sessions.find_client(&client_id).await {
+    Some(client) if client.post(data) => reply(StatusCode::ACCEPTED, &json!({ "client_id": client_id })),
+    _ => error(StatusCode::NOT_FOUND, "no such client")
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Config;
+  use crate::transit::simulation_task::SimulationHandle;
+  use crate::transit::transit_service::TransitServer;
+
+  fn api() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let config = Config::default();
+    let server = SimulationHandle::spawn("test", TransitServer::new(&config), None);
+    routes(server.clone(), Sessions::new(server, &config))
+  }
+
+  async fn request(api: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static), method: &str, path: &str, body: &str) -> (u16, Value) {
+    let response = warp::test::request().method(method).path(path).body(body).reply(api).await;
+    (response.status().as_u16(), serde_json::from_slice(response.body()).unwrap_or(Value::Null))
+  }
+
+  #[tokio::test]
+  async fn answers_with_status_codes() {
+    let api = api();
+    let robot = json!({
+      "type": "robot", "name": "Alice", "mesh": "assets/model/robot.glb",
+      "position": [-300., 264., 90.], "scale": [0.25, 0.25, 0.25], "direction": [1, 0, 0], "speed": 30.0
+    });
+    let (status, created) = request(&api, "POST", "/api/entities", &robot.to_string()).await;
+    assert_eq!(status, 201);
+    let entity = format!("/api/entities/{}", created["id"]);
+    assert_eq!(request(&api, "GET", &entity, "").await, (200, created.clone()));
+    assert_eq!(request(&api, "GET", "/api/entities", "").await, (200, json!([created])));
+    assert_eq!(request(&api, "GET", "/api/entities/999", "").await.0, 404);
+    for body in ["{ not json", "[1, 2]"] {
+      assert_eq!(request(&api, "POST", "/api/entities", body).await.0, 400, "{}", body);
+    }
+    assert_eq!(request(&api, "POST", "/api/trips", r#"{ "name": "Bob", "start": [0, 264, 0], "end": [10, 264, 0], "search": "astar" }"#).await.0, 404);
+    assert_eq!(request(&api, "GET", "/api/graph/path?from=1,2&to=3,4,5", "").await.0, 400);
+    assert_eq!(request(&api, "POST", "/post/nobody", "{}").await.0, 404);
+    assert_eq!(request(&api, "DELETE", &entity, "").await.0, 200);
+    assert_eq!(request(&api, "DELETE", &entity, "").await.0, 404);
+  }
+}
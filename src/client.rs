@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{warn, Instrument};
+use warp::ws::Message;
+
+use crate::Server;
+use crate::encoding::Encoding;
+use crate::outbox::Outbox;
+use crate::subscription::Subscription;
+use crate::transit::outgoing::Outgoing;
+use crate::transit::transit_service::{COMMANDS, REQUESTS};
+
+// commands a client handles itself instead of passing them on
+const CLIENT_COMMANDS: [&str; 2] = ["SetEncoding", "Subscribe"];
+
+// How the rest of the server reaches a connected client, for metrics and for
+// commands posted over http on its behalf.
+#[derive(Clone)]
+pub struct ClientHandle {
+  pub outbox: Arc<Outbox>,
+  pub posts: mpsc::UnboundedSender<Value>
+}
+
+impl ClientHandle {
+  pub fn post(&self, data: Value) -> bool {
+    self.posts.send(data).is_ok()
+  }
+}
+
+// A websocket connection. How it is sent to is settled here, everything else
+// it sends goes on to the simulation.
+pub struct Client {
+  pub client_id: String,
+  pub outbox: Arc<Outbox>,
+  pub encoding: Encoding,
+  pub subscription: Arc<Subscription>,
+  // what a kill or a restarting script must carry, both are refused without one
+  admin_token: Option<String>
+}
+
+impl Client {
+  pub fn new(client_id: String, outbox: Arc<Outbox>, encoding: Encoding, admin_token: Option<String>) -> Self {
+    Client { client_id, outbox, encoding, subscription: Arc::default(), admin_token }
+  }
+  pub fn recieve(&mut self, msg: &Message, server: &Server) {
+    let data = if let Ok(text) = msg.to_str() {
+      match serde_json::from_str::<Value>(text) {
+        Ok(data) => data,
+        Err(e) => return warn!("invalid message: {}", e)
+      }
+    } else if msg.is_binary() {
+      match self.encoding.decode(msg.as_bytes()) {
+        Some(data) => data,
+        None => return warn!(encoding = self.encoding.name(), "could not decode message")
+      }
+    } else {
+      return;
+    };
+    self.handle(data, server);
+  }
+  pub fn handle(&mut self, data: Value, server: &Server) {
+    match data["command"].as_str() {
+      Some("SetEncoding") => self.set_encoding(&data),
+      Some("Subscribe") => self.subscribe(&data),
+      Some("kill") => self.kill(data, server),
+      Some("runScript") if data["init"] == true => self.restart(data, server),
+      Some(command) if REQUESTS.contains(&command) => self.request(data, server),
+      _ => server.send(data)
+    }
+  }
+  // Queues an event for the client, Err when it has fallen too far behind
+  // to keep up
+  pub fn send(&self, outgoing: &Arc<Outgoing>) -> Result<(), String> {
+    if !outgoing.wants(&self.subscription) { return Ok(()); }
+    if outgoing.is_droppable() {
+      return self.outbox.push_update(outgoing.clone(), self.encoding, self.subscription.clone());
+    }
+    match outgoing.frame(self.encoding, &self.subscription) {
+      Some(frame) => self.outbox.push_frame(frame),
+      None => Ok(())
+    }
+  }
+  // the first message a client gets, telling it who it is and what it can ask
+  pub fn welcome(&self, time: f64) {
+    self.reply("Welcome", &json!({
+      "client_id": self.client_id,
+      "version": env!("CARGO_PKG_VERSION"),
+      "time": time,
+      "commands": CLIENT_COMMANDS.iter().chain(COMMANDS.iter()).collect::<Vec<&&str>>()
+    }));
+  }
+  fn reply(&self, event: &str, details: &Value) {
+    reply(&self.outbox, event, details);
+  }
+  fn set_encoding(&mut self, data: &Value) {
+    match data["encoding"].as_str().and_then(Encoding::from_name) {
+      Some(encoding) => {
+        self.encoding = encoding;
+        self.reply("EncodingChanged", &json!({ "encoding": encoding.name() }));
+      },
+      None => warn!("unknown encoding {}", data["encoding"])
+    }
+  }
+  // Checks the admin token a command carries and takes it out, so that it
+  // is not passed along to end up in an event log.
+  fn authorise(&self, data: &mut Value) -> Result<(), String> {
+    match (&self.admin_token, data["token"].as_str()) {
+      (None, _) => Err(format!("{} is disabled on this server", data["command"].as_str().unwrap_or_default())),
+      (Some(token), Some(given)) if token == given => {
+        if let Some(data) = data.as_object_mut() {
+          data.remove("token");
+        }
+        Ok(())
+      },
+      _ => Err("invalid token".to_string())
+    }
+  }
+  fn reject(&self, command: &str, reason: &str) {
+    warn!("{} refused: {}", command, reason);
+    self.reply("CommandRejected", &json!({ "command": command, "reason": reason }));
+  }
+  fn kill(&self, mut data: Value, server: &Server) {
+    match self.authorise(&mut data) {
+      Ok(()) => {
+        warn!(mode = data["mode"].as_str().unwrap_or("reset"), "simulation killed");
+        server.send(data);
+      },
+      Err(reason) => self.reject("kill", &reason)
+    }
+  }
+  // a script starting the world over resets it for every client, as a kill does
+  fn restart(&self, mut data: Value, server: &Server) {
+    match self.authorise(&mut data) {
+      Ok(()) => {
+        warn!("simulation restarted by a script");
+        server.send(data);
+      },
+      Err(reason) => self.reject("runScript", &reason)
+    }
+  }
+  // Runs a request once the commands sent before it have run, its answer or
+  // failure coming back to this client alone.
+  fn request(&self, data: Value, server: &Server) {
+    let (server, outbox) = (server.clone(), self.outbox.clone());
+    let command = data["command"].as_str().unwrap_or_default().to_string();
+    tokio::spawn(async move {
+      match server.call(move |s| s.request(data)).await {
+        Some(Ok(Some((event, details)))) => reply(&outbox, event, &details),
+        Some(Err(reason)) => {
+          warn!("{} failed: {}", command, reason);
+          reply(&outbox, "CommandFailed", &json!({ "command": command, "reason": reason }));
+        },
+        _ => ()
+      }
+    }.in_current_span());
+  }
+  fn subscribe(&mut self, data: &Value) {
+    match Subscription::from_json(data) {
+      Ok(subscription) => {
+        self.reply("Subscribed", &subscription.to_json());
+        self.subscription = Arc::new(subscription);
+      },
+      Err(e) => warn!("invalid subscription: {}", e)
+    }
+  }
+}
+
+// events that only concern one client, always sent as json
+fn reply(outbox: &Outbox, event: &str, details: &Value) {
+  let _ = outbox.push_frame(Message::text(json!({
+    "event": event,
+    "details": details
+  }).to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Config;
+  use crate::sessions::Sessions;
+  use crate::transit::simulation_task::SimulationHandle;
+  use crate::transit::transit_service::TransitServer;
+
+  async fn next_message(outbox: &Outbox) -> Value {
+    serde_json::from_str(outbox.next().await.unwrap().to_str().unwrap()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn welcomes_clients_with_who_they_are() {
+    let client = Client::new("abc".to_string(), Arc::new(Outbox::new()), Encoding::Json, None);
+    client.welcome(2.5);
+    let welcome = next_message(&client.outbox).await;
+    assert_eq!(welcome["event"], "Welcome");
+    assert_eq!(welcome["details"]["client_id"], "abc");
+    assert_eq!(welcome["details"]["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(welcome["details"]["time"], 2.5);
+    let commands = welcome["details"]["commands"].as_array().unwrap();
+    assert_eq!(commands.len(), CLIENT_COMMANDS.len() + COMMANDS.len());
+    for command in ["SetEncoding", "Subscribe", "CreateEntity", "SaveSnapshot", "kill"] {
+      assert!(commands.contains(&json!(command)), "{}", command);
+    }
+  }
+
+  #[tokio::test]
+  async fn commands_posted_over_http_reach_the_client() {
+    let config = Config::default();
+    let server = SimulationHandle::spawn("test", TransitServer::new(&config), None);
+    let (posts, mut posted) = mpsc::unbounded_channel();
+    server.add_client("abc", ClientHandle { outbox: Arc::new(Outbox::new()), posts });
+    let api = crate::api::routes(server.clone(), Sessions::new(server, &config));
+    let command = json!({ "command": "SetSeed", "seed": 4 });
+    let response = warp::test::request().method("POST").path("/post/abc").body(command.to_string()).reply(&api).await;
+    assert_eq!(response.status(), 202);
+    assert_eq!(posted.try_recv().ok(), Some(command));
+    let response = warp::test::request().method("POST").path("/post/xyz").body("{}").reply(&api).await;
+    assert_eq!(response.status(), 404);
+  }
+}
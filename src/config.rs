@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use serde::Deserialize;
+
+use crate::graph::{graph::Graph, parsers::obj_graph_parser};
+use crate::logging;
+use crate::transit::dispatch::DispatchPolicy;
+
+// tick and broadcast rates, in hz, that give a usable interval
+const RATES: (f64, f64) = (0.001, 1000.);
+
+pub const USAGE: &str = "\
+Usage: simulation_rust [<port> [<web dir>]] [options]
+
+Options:
+  --config <file>          read settings from a json file, options given here win
+  --bind <address>         address to listen on (default 127.0.0.1)
+  --port <port>            port to listen on (default 8081)
+  --web <dir>              directory the viewer is served from (default web)
+  --graph <file>           obj file with the route network
+  --scene <file>           scene to run at startup
+  --tick-rate <hz>         advance the simulation on the server's own clock
+  --broadcast-rate <hz>    send pose updates at most this often
+  --seed <n>               seed for the random number generator
+  --speed <type>=<speed>   default speed for entities of a type, may be repeated
+  --dispatch <policy>      how trips are given to drones: pooled or nearest
+  --snapshot <file>        load a snapshot at startup
+  --record <file>          record commands and events to an event log
+  --replay <file>          replay an event log
+  --headless <seconds>     run for this many sim seconds without clients and exit
+  --metrics <file>         where a headless run writes its metrics
+  --final-snapshot <file>  save a snapshot when the server is stopped
+  --final-metrics <file>   write the metrics when the server is stopped
+  --max-sessions <n>       most sessions open at once, the default one included (default 16)
+  --data-dir <dir>         where clients save and load snapshots and write metrics (default data)
+  --admin-token <token>    token a client must give to kill or restart the simulation,
+                           which is refused to everyone without one
+  --log <filter>           which log events to show, e.g. debug or warn,simulation_rust=info
+  --log-format <format>    pretty or json (default pretty)
+  --help                   show this message";
+
+// Server settings, from a json config file with the command line on top. Keys
+// in the file are the option names with `_` for `-`, `speeds` maps entity
+// types to speeds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub bind: String,
+  pub port: u16,
+  pub web: String,
+  pub graph: String,
+  pub scene: Option<String>,
+  pub tick_rate: Option<f64>,
+  pub broadcast_rate: Option<f64>,
+  pub seed: Option<u64>,
+  pub speeds: BTreeMap<String, f64>,
+  pub dispatch: String,
+  pub snapshot: Option<String>,
+  pub record: Option<String>,
+  pub replay: Option<String>,
+  pub headless: Option<f64>,
+  pub metrics: Option<String>,
+  pub final_snapshot: Option<String>,
+  pub final_metrics: Option<String>,
+  pub max_sessions: usize,
+  pub data_dir: String,
+  pub admin_token: Option<String>,
+  pub log: String,
+  pub log_format: String,
+  // the route network read from `graph` by `validate`, shared by every session
+  #[serde(skip)]
+  pub network: Arc<Graph>
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      bind: "127.0.0.1".to_string(),
+      port: 8081,
+      web: "web".to_string(),
+      graph: "web/assets/model/routes.obj".to_string(),
+      scene: None,
+      tick_rate: None,
+      broadcast_rate: None,
+      seed: None,
+      speeds: BTreeMap::new(),
+      dispatch: DispatchPolicy::default().name().to_string(),
+      snapshot: None,
+      record: None,
+      replay: None,
+      headless: None,
+      metrics: None,
+      final_snapshot: None,
+      final_metrics: None,
+      max_sessions: 16,
+      data_dir: "data".to_string(),
+      admin_token: None,
+      log: "info".to_string(),
+      log_format: "pretty".to_string(),
+      network: Arc::default()
+    }
+  }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> where T::Err: std::fmt::Display {
+  value.parse::<T>().map_err(|e| format!("{} {}: {}", flag, value, e))
+}
+
+impl Config {
+  pub fn load(path: &str) -> Result<Self, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))
+  }
+  // The config file named by --config, if any, overridden by the rest of the
+  // arguments. None when --help was asked for.
+  pub fn from_args(args: &[String]) -> Result<Option<Self>, String> {
+    let mut options = vec![];
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.strip_prefix("--") {
+        Some("help") => return Ok(None),
+        Some(flag) => options.push((flag, args.next().ok_or(format!("--{} needs a value", flag))?)),
+        None => positional.push(arg)
+      }
+    }
+    let mut config = match options.iter().rev().find(|(flag, _)| *flag == "config") {
+      Some((_, path)) => Config::load(path)?,
+      None => Config::default()
+    };
+    match positional.as_slice() {
+      [] => (),
+      [port] => config.port = parse("port", port)?,
+      [port, web] => {
+        config.port = parse("port", port)?;
+        config.web = web.to_string();
+      },
+      _ => return Err(format!("unexpected argument {}", positional[2]))
+    }
+    for (flag, value) in options {
+      let value = value.clone();
+      match flag {
+        "config" => (),
+        "bind" => config.bind = value,
+        "port" => config.port = parse("--port", &value)?,
+        "web" => config.web = value,
+        "graph" => config.graph = value,
+        "scene" => config.scene = Some(value),
+        "tick-rate" => config.tick_rate = Some(parse("--tick-rate", &value)?),
+        "broadcast-rate" => config.broadcast_rate = Some(parse("--broadcast-rate", &value)?),
+        "seed" => config.seed = Some(parse("--seed", &value)?),
+        "speed" => {
+          let (kind, speed) = value.split_once('=').ok_or(format!("--speed {}: expected <type>=<speed>", value))?;
+          config.speeds.insert(kind.to_string(), parse("--speed", speed)?);
+        },
+        "dispatch" => config.dispatch = value,
+        "snapshot" => config.snapshot = Some(value),
+        "record" => config.record = Some(value),
+        "replay" => config.replay = Some(value),
+        "headless" => config.headless = Some(parse("--headless", &value)?),
+        "metrics" => config.metrics = Some(value),
+        "final-snapshot" => config.final_snapshot = Some(value),
+        "final-metrics" => config.final_metrics = Some(value),
+        "max-sessions" => config.max_sessions = parse("--max-sessions", &value)?,
+        "data-dir" => config.data_dir = value,
+        "admin-token" => config.admin_token = Some(value),
+        "log" => config.log = value,
+        "log-format" => config.log_format = value,
+        _ => return Err(format!("unknown option --{}", flag))
+      }
+    }
+    Ok(Some(config))
+  }
+  // everything wrong with the settings, empty when they can be used. The
+  // graph is read here so it is only read once.
+  pub fn validate(&mut self) -> Vec<String> {
+    let mut errors = vec![];
+    if self.bind.parse::<IpAddr>().is_err() {
+      errors.push(format!("bind: {} is not an ip address", self.bind));
+    }
+    if self.headless.is_none() && !Path::new(&self.web).is_dir() {
+      errors.push(format!("web: {} is not a directory", self.web));
+    }
+    let files = [
+      ("scene", self.scene.as_ref()),
+      ("snapshot", self.snapshot.as_ref()), ("replay", self.replay.as_ref())
+    ];
+    for (name, path) in files {
+      if let Some(path) = path.filter(|p| !Path::new(p).is_file()) {
+        errors.push(format!("{}: {} is not a file", name, path));
+      }
+    }
+    match obj_graph_parser(&self.graph) {
+      Ok(graph) => self.network = Arc::new(graph),
+      Err(e) => errors.push(format!("graph: {}", e))
+    }
+    if self.max_sessions == 0 {
+      errors.push("max_sessions: must be at least 1".to_string());
+    }
+    if Path::new(&self.data_dir).exists() && !Path::new(&self.data_dir).is_dir() {
+      errors.push(format!("data_dir: {} is not a directory", self.data_dir));
+    }
+    if let Some(headless) = self.headless.filter(|s| !s.is_finite() || *s <= 0.) {
+      errors.push(format!("headless: {} must be a positive number", headless));
+    }
+    let (min, max) = RATES;
+    for (name, rate) in [("tick_rate", self.tick_rate), ("broadcast_rate", self.broadcast_rate)] {
+      if let Some(rate) = rate.filter(|r| !(min..=max).contains(r)) {
+        errors.push(format!("{}: {} must be between {} and {} hz", name, rate, min, max));
+      }
+    }
+    for (kind, speed) in self.speeds.iter() {
+      if !["drone", "robot", "human", "helicopter"].contains(&kind.as_str()) {
+        errors.push(format!("speeds: unknown entity type {}", kind));
+      }
+      if !speed.is_finite() || *speed < 0. {
+        errors.push(format!("speeds: {} for {} must not be negative", speed, kind));
+      }
+    }
+    if DispatchPolicy::from_name(&self.dispatch).is_none() {
+      errors.push(format!("dispatch: unknown policy {}, expected pooled or nearest", self.dispatch));
+    }
+    if self.record.is_some() && self.replay.is_some() {
+      errors.push("record and replay cannot be used together".to_string());
+    }
+    if self.admin_token.as_ref().is_some_and(|t| t.trim().is_empty()) {
+      errors.push("admin_token: must not be empty".to_string());
+    }
+    if !logging::is_valid_filter(&self.log) {
+      errors.push(format!("log: {} is not a valid filter", self.log));
+    }
+    if !logging::FORMATS.contains(&self.log_format.as_str()) {
+      errors.push(format!("log_format: unknown format {}, expected pretty or json", self.log_format));
+    }
+    if self.metrics.is_some() && self.headless.is_none() {
+      errors.push("metrics are only written by a headless run".to_string());
+    }
+    if self.headless.is_some() && (self.final_snapshot.is_some() || self.final_metrics.is_some()) {
+      errors.push("final_snapshot and final_metrics are written when a server stops, not by a headless run".to_string());
+    }
+    errors
+  }
+  pub fn get_addr(&self) -> SocketAddr {
+    SocketAddr::new(self.bind.parse().unwrap_or(IpAddr::from([127, 0, 0, 1])), self.port)
+  }
+  pub fn get_dispatch(&self) -> DispatchPolicy {
+    DispatchPolicy::from_name(&self.dispatch).unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rates_must_give_a_usable_interval() {
+    for rate in [1e-300, 0., -1., f64::NAN, 1e300] {
+      let mut config = Config { tick_rate: Some(rate), broadcast_rate: Some(rate), ..Config::default() };
+      assert_eq!(config.validate().iter().filter(|e| e.contains("rate")).count(), 2, "{}", rate);
+    }
+    let mut config = Config { tick_rate: Some(60.), broadcast_rate: Some(0.5), ..Config::default() };
+    assert_eq!(config.validate(), Vec::<String>::new());
+  }
+
+  #[test]
+  fn graphs_are_read_once_and_checked() {
+    let mut config = Config::default();
+    assert_eq!(config.validate(), Vec::<String>::new());
+    assert!(config.network.nodes.len() > 1);
+    let path = std::env::temp_dir().join(format!("graph-{}.obj", std::process::id()));
+    for obj in ["v 0 0 0\nv 1 0 x\n", "v 0 0 0\nv 1 0 0\nl 1 3\n", "v 0 0\n", "l 1\n"] {
+      fs::write(&path, obj).unwrap();
+      let mut config = Config { graph: path.to_string_lossy().to_string(), ..Config::default() };
+      assert_eq!(config.validate().len(), 1, "{}", obj);
+    }
+    fs::write(&path, "v 0 0 0\r\nv 1 0 0\r\nl 1 2\r\n").unwrap();
+    assert_eq!(obj_graph_parser(&path.to_string_lossy()).map(|g| g.adjacency_list), Ok(vec![vec![], vec![2], vec![1]]));
+    fs::remove_file(&path).unwrap();
+    config.graph = "missing.obj".to_string();
+    assert!(config.validate()[0].starts_with("graph: missing.obj"));
+  }
+}
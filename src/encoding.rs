@@ -0,0 +1,92 @@
+use serde_json::Value;
+use warp::ws::Message;
+
+// How a client wants pose updates framed. Everything else stays JSON text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Encoding {
+  Json,
+  MessagePack,
+  Cbor
+}
+
+impl Encoding {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "json" => Some(Encoding::Json),
+      "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+      "cbor" => Some(Encoding::Cbor),
+      _ => None
+    }
+  }
+  pub fn name(&self) -> &'static str {
+    match self {
+      Encoding::Json => "json",
+      Encoding::MessagePack => "msgpack",
+      Encoding::Cbor => "cbor"
+    }
+  }
+  // websocket subprotocols are "web_server" for json and "web_server.<name>"
+  pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+    match protocol.trim() {
+      "web_server" => Some(Encoding::Json),
+      p => Encoding::from_name(p.strip_prefix("web_server.")?)
+    }
+  }
+  pub fn subprotocol(&self) -> String {
+    match self {
+      Encoding::Json => "web_server".to_string(),
+      e => format!("web_server.{}", e.name())
+    }
+  }
+  pub fn encode(&self, value: &Value) -> Message {
+    let bytes = match self {
+      Encoding::Json => None,
+      Encoding::MessagePack => rmp_serde::to_vec_named(value).ok(),
+      Encoding::Cbor => {
+        let mut bytes = vec![];
+        ciborium::into_writer(value, &mut bytes).ok().map(|_| bytes)
+      }
+    };
+    match bytes {
+      Some(bytes) => Message::binary(bytes),
+      None => Message::text(value.to_string())
+    }
+  }
+  pub fn decode(&self, bytes: &[u8]) -> Option<Value> {
+    match self {
+      Encoding::Json => serde_json::from_slice(bytes).ok(),
+      Encoding::MessagePack => rmp_serde::from_slice(bytes).ok(),
+      Encoding::Cbor => ciborium::from_reader(bytes).ok()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn updates_survive_every_encoding() {
+    let update = json!({ "event": "WorldUpdate", "details": { "time": 1.5, "entities": [
+      { "id": 3, "pos": [-300.25, 264., 90.], "dir": [1., 0., 0.] },
+      { "id": 4, "details": { "name": "Alice", "scale": [0.25, 0.25, 0.25], "color": null } }
+    ]}});
+    for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+      let frame = encoding.encode(&update);
+      assert_eq!(frame.is_binary(), encoding != Encoding::Json, "{}", encoding.name());
+      assert_eq!(encoding.decode(frame.as_bytes()), Some(update.clone()), "{}", encoding.name());
+    }
+    assert_eq!(Encoding::Cbor.decode(&[0xff, 0x00]), None);
+  }
+
+  #[test]
+  fn names_and_subprotocols_match() {
+    for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+      assert_eq!(Encoding::from_name(encoding.name()), Some(encoding));
+      assert_eq!(Encoding::from_subprotocol(&encoding.subprotocol()), Some(encoding));
+    }
+    assert_eq!(Encoding::from_subprotocol(" web_server.cbor"), Some(Encoding::Cbor));
+    assert_eq!(Encoding::from_subprotocol("web_server.xml"), None);
+  }
+}
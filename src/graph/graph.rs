@@ -2,6 +2,10 @@ use crate::math::vector3::Vector3;
 
 use super::routing::SearchStrategy;
 
+pub fn path_length(path: &[Vector3]) -> f64 {
+  path.windows(2).map(|w| w[0].distance(&w[1])).sum()
+}
+
 #[derive(Debug)]
 pub struct GraphNode {
   id: i32,
@@ -20,6 +24,9 @@ pub struct Graph {
   pub adjacency_list: Vec<Vec<i32>>,
   pub nodes: Vec<GraphNode>
 }
+impl Default for Graph {
+  fn default() -> Self { Graph::new() }
+}
 impl Graph {
   pub fn new() -> Self {
     Graph {
@@ -34,6 +41,19 @@ impl Graph {
   pub fn add_edge(&mut self, n1: i32, n2: i32) {
     self.adjacency_list[n1 as usize].push(n2);
   }
+  pub fn bounding_box(&self) -> Option<(Vector3, Vector3)> {
+    let mut nodes = self.nodes.iter()
+      .filter(|n| !self.adjacency_list[n.id as usize].is_empty())
+      .map(|n| n.position);
+    let first = nodes.next()?;
+    Some(nodes.fold((first, first), |(mut min, mut max), p| {
+      for i in 0..3 {
+        min[i] = min[i].min(p[i]);
+        max[i] = max[i].max(p[i]);
+      }
+      (min, max)
+    }))
+  }
   pub fn nearest_node(&self, position: Vector3) -> i32 {
     let (mut min_i, mut min_d) = (-1, f64::INFINITY);
     for i in 0..self.nodes.len() {
@@ -46,13 +66,11 @@ impl Graph {
     min_i 
   }
   pub fn get_path(&self, start: Vector3, end: Vector3, strat: Box<dyn SearchStrategy>) -> Option<Vec<Vector3>> {
+    if self.nodes.is_empty() { return None; }
     let n1 = self.nearest_node(start);
     let n2 = self.nearest_node(end);
-    match strat.get_path(self, n1, n2) {
-      Some(p) => Some(p.iter()
-        .map(|i| self.nodes[*i as usize].get_position())
-        .collect()),
-      None => None
-    }
+    strat.get_path(self, n1, n2).map(|p| p.iter()
+      .map(|i| self.nodes[*i as usize].get_position())
+      .collect())
   }
 }
@@ -4,26 +4,39 @@ use crate::math::vector3::Vector3;
 
 use super::graph::Graph;
 
-pub fn obj_graph_parser(file: String) -> Graph {
+// The route network in an obj file, `v` lines are nodes and `l` lines join
+// two of them both ways. Node 0 is a placeholder so obj's 1-based indices
+// can be used as they are.
+pub fn obj_graph_parser(file: &str) -> Result<Graph, String> {
   let mut g = Graph::new();
   g.add_node(Vector3::new(-1000., -1000., -1000.));
-  let f = fs::read_to_string(file).unwrap();
-  let lines = f.split("\n")
-    .map(|s| s.split(" ").collect::<Vec<&str>>())
-    .collect::<Vec<Vec<&str>>>();
-  for l in lines {
-    match l[0] {
-      "v" =>
-        g.add_node(Vector3::from_vec(&l[1..4].iter()
-          .map(|s| s.parse::<f64>().unwrap())
-          .collect::<Vec<f64>>())),
-      "l" => {
-        let (v1, v2) = (l[1].parse::<i32>().unwrap(), l[2].parse::<i32>().unwrap());
+  let f = fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+  for (n, line) in f.lines().enumerate() {
+    let l = line.split_whitespace().collect::<Vec<&str>>();
+    let error = || format!("{} line {}: cannot read {}", file, n + 1, line.trim());
+    match l.first() {
+      Some(&"v") => {
+        let v = l[1..].iter().take(3)
+          .map(|s| s.parse::<f64>())
+          .collect::<Result<Vec<f64>, _>>()
+          .map_err(|_| error())?;
+        if v.len() < 3 { return Err(error()); }
+        g.add_node(Vector3::from_vec(&v));
+      }
+      Some(&"l") => {
+        let ends = l[1..].iter().take(2)
+          .map(|s| s.parse::<i32>())
+          .collect::<Result<Vec<i32>, _>>()
+          .map_err(|_| error())?;
+        let (v1, v2) = match ends[..] {
+          [v1, v2] if [v1, v2].iter().all(|v| *v > 0 && (*v as usize) < g.nodes.len()) => (v1, v2),
+          _ => return Err(error())
+        };
         g.add_edge(v1, v2);
         g.add_edge(v2, v1);
       }
       _ => ()
     }
-  };
-  g
+  }
+  Ok(g)
 }
\ No newline at end of file
@@ -7,22 +7,13 @@ struct F64(f64);
 impl Eq for F64 {}
 impl PartialOrd for F64 {
   fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    if self.0 < other.0 {
-      return Some(Ordering::Greater)
-    } else if self.0 > other.0 {
-      return  Some(Ordering::Less);
-    } else {
-      return Some(Ordering::Equal);
-    }
+    Some(self.cmp(other))
   }
 }
 impl Ord for F64 {
+  // reversed so that BinaryHeap pops the smallest distance first
   fn cmp(&self, other: &Self) -> Ordering {
-    if let Some(ordering) = other.partial_cmp(self) {
-      ordering
-    } else {
-      Ordering::Less
-    }
+    other.0.total_cmp(&self.0)
   }
 }
 
@@ -36,14 +27,16 @@ impl DepthFirstSearch {
     DepthFirstSearch {  }
   }
 }
+impl Default for DepthFirstSearch {
+  fn default() -> Self { DepthFirstSearch::new() }
+}
 impl SearchStrategy for DepthFirstSearch {
   fn get_path(&self, g: &Graph, start: i32, end: i32) -> Option<Vec<i32>> {
     let mut s: Vec<(i32, i32)> = vec![];
     let mut v: HashSet<i32> = HashSet::new();
     let mut parents: HashMap<i32, i32> = HashMap::new();
     s.push((start, -1));
-    while !s.is_empty() {
-      let (n, p) = s.pop().unwrap();
+    while let Some((n, p)) = s.pop() {
       if v.contains(&n) { continue; }
       v.insert(n);
       parents.insert(n, p);
@@ -72,6 +65,9 @@ impl Dijkstras {
     Dijkstras {  }
   }
 }
+impl Default for Dijkstras {
+  fn default() -> Self { Dijkstras::new() }
+}
 impl SearchStrategy for Dijkstras {
   fn get_path(&self, g: &Graph, start: i32, end: i32) -> Option<Vec<i32>> {
     let mut q: BinaryHeap<(F64, (i32, i32))> = BinaryHeap::new();
@@ -123,6 +119,9 @@ impl AStar {
     AStar { heuristic: f }
   }
 }
+impl Default for AStar {
+  fn default() -> Self { AStar::new() }
+}
 impl SearchStrategy for AStar {
   fn get_path(&self, g: &Graph, start: i32, end: i32) -> Option<Vec<i32>> {
     let mut q: BinaryHeap<(F64, (i32, i32, F64))> = BinaryHeap::new();
@@ -0,0 +1,23 @@
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+pub const FORMATS: [&str; 2] = ["pretty", "json"];
+
+// Sends log events to stdout. `filter` takes the usual directives, `info` or
+// `warn,simulation_rust::transit=debug`, and json output carries the fields of
+// every enclosing span so that one client or trip can be picked out of a run.
+pub fn init(filter: &str, format: &str) -> Result<(), String> {
+  let filter = EnvFilter::try_new(filter).map_err(|e| format!("log {}: {}", filter, e))?;
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_ansi(io::stdout().is_terminal());
+  let result = match format {
+    "json" => builder.json().with_current_span(false).with_span_list(true).try_init(),
+    _ => builder.pretty().try_init()
+  };
+  result.map_err(|e| e.to_string())
+}
+
+pub fn is_valid_filter(filter: &str) -> bool {
+  EnvFilter::try_new(filter).is_ok()
+}
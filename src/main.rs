@@ -1,16 +1,29 @@
-use futures_util::{FutureExt, StreamExt};
+use futures_util::{SinkExt, StreamExt};
 use warp::{Filter, Reply, Rejection};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
 use uuid::Uuid;
+use warp::http::StatusCode;
 use warp::ws::WebSocket;
 
+pub mod api;
+pub mod client;
+pub mod config;
+pub mod encoding;
+pub mod logging;
+pub mod outbox;
+pub mod sessions;
+pub mod subscription;
+
 pub mod math {
   pub mod vector3;
 }
 
 pub mod graph {
+  #[allow(clippy::module_inception)]
   pub mod graph;
   pub mod parsers;
   pub mod routing;
@@ -26,83 +39,240 @@ pub mod transit {
     pub mod human;
     pub mod helicopter;
   }
+  pub mod delta;
+  pub mod dispatch;
+  pub mod event_log;
   pub mod factory;
+  pub mod metrics;
+  pub mod notifications;
+  pub mod outgoing;
+  pub mod simulation_task;
   pub mod strategy;
+  pub mod trip;
+  pub mod world;
 }
 
+use client::{Client, ClientHandle};
+use config::{Config, USAGE};
+use encoding::Encoding;
+use outbox::Outbox;
+use sessions::{Sessions, DEFAULT_SESSION};
+use transit::metrics::Exposition;
+use transit::simulation_task::SimulationHandle;
 use transit::transit_service::TransitServer;
 
 // https://tms-dev-blog.com/build-basic-rust-websocket-server/
 
-#[derive(Debug, Clone)]
-pub struct Client {
-  pub client_id: String,
-  pub sender: Option<mpsc::UnboundedSender<std::result::Result<warp::ws::Message, warp::Error>>>,
-}
+type Server = SimulationHandle;
 
-type Server = Arc<Mutex<TransitServer>>;
+// how long clients get to be sent what is left when the server stops
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() {
-  let args: Vec<String> = std::env::args().collect();
-  if args.len() < 3 {
-    print!("Usage: cargo run <port> web");
-    return;
-  }
-  let port = match args[1].parse::<u16>() {
-    Ok(v) => v,
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let mut config = match Config::from_args(&args) {
+    Ok(Some(config)) => config,
+    Ok(None) => return println!("{}", USAGE),
     Err(e) => {
-      println!("{} is not a valid port: {}", args[1], e);
-      return;
+      println!("{}\n\n{}", e, USAGE);
+      std::process::exit(2);
     }
   };
-  let web_dir = args[2].clone();
-  let server: Server = Arc::new(Mutex::new(TransitServer::new()));
-  let websocket_con = warp::ws()
-    .and(warp::any().map(move || server.clone()))
+  let errors = config.validate();
+  if !errors.is_empty() {
+    for e in errors {
+      println!("invalid config: {}", e);
+    }
+    std::process::exit(1);
+  }
+  if let Err(e) = logging::init(&config.log, &config.log_format) {
+    println!("could not set up logging: {}", e);
+  }
+  let mut transit_server = TransitServer::new(&config);
+  if let Some(path) = &config.snapshot {
+    if let Err(e) = transit_server.load_snapshot_file(path) {
+      error!("could not load snapshot: {}", e);
+      return;
+    }
+  }
+  if let Some(path) = &config.replay {
+    if let Err(e) = transit_server.replay(path) {
+      error!("could not load event log: {}", e);
+      return;
+    }
+  } else if let Some(path) = &config.record {
+    if let Err(e) = transit_server.record(path) {
+      error!("could not record event log: {}", e);
+      return;
+    }
+  }
+  if let Some(path) = &config.scene {
+    if let Err(e) = transit_server.run_scene(path) {
+      error!("could not run scene: {}", e);
+      return;
+    }
+  }
+  if let Some(seconds) = config.headless {
+    transit_server.run_headless(seconds);
+    if let Some(path) = &config.metrics {
+      match transit_server.write_metrics(path) {
+        Ok(files) => info!("metrics written to {}", files.join(", ")),
+        Err(e) => error!("could not write metrics: {}", e)
+      }
+    }
+    return;
+  }
+  let server = SimulationHandle::spawn(DEFAULT_SESSION, transit_server, config.tick_rate);
+  let sessions = Sessions::new(server.clone(), &config);
+  sessions.spawn_reaper();
+  let api = api::routes(server.clone(), sessions.clone());
+  let metrics_sessions = sessions.clone();
+  let metrics = warp::path("metrics")
+    .and(warp::path::end())
+    .and(warp::get())
+    .and(warp::any().map(move || metrics_sessions.clone()))
+    .and_then(handle_metrics);
+  // `/ws/{session}` joins or creates a named session, `/` the default one
+  let session_name = warp::path!("ws" / String).or(warp::path::end().map(|| DEFAULT_SESSION.to_string())).unify();
+  let websocket_con = session_name
+    .and(warp::ws())
+    .and(warp::header::optional::<String>("sec-websocket-protocol"))
+    .and(warp::any().map({
+      let sessions = sessions.clone();
+      move || sessions.clone()
+    }))
     .and_then(handle_connection)
     .with(warp::cors().allow_any_origin());
-  warp::serve(websocket_con.or(warp::fs::dir(web_dir)))
-    .run(([127, 0, 0, 1], port))
-    .await;
+  let (_, serving) = warp::serve(api.or(metrics).or(websocket_con).or(warp::fs::dir(config.web.clone())))
+    .bind_with_graceful_shutdown(config.get_addr(), shutdown_signal());
+  serving.await;
+  info!("shutting down");
+  if let Some(path) = config.final_snapshot.clone() {
+    match server.call(move |s| s.write_snapshot(&path)).await {
+      Some(Ok(_)) => info!("final snapshot written"),
+      Some(Err(e)) => error!("could not write final snapshot: {}", e),
+      None => ()
+    }
+  }
+  if let Some(path) = config.final_metrics.clone() {
+    match server.call(move |s| s.write_metrics(&path)).await {
+      Some(Ok(files)) => info!("metrics written to {}", files.join(", ")),
+      Some(Err(e)) => error!("could not write metrics: {}", e),
+      None => ()
+    }
+  }
+  sessions.shutdown().await;
+  // give the clients a moment to be sent the shutdown event
+  let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+  while sessions.get_client_count().await > 0 && Instant::now() < deadline {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+}
+
+// Ctrl-C, or SIGTERM where there is such a thing. New connections are refused
+// from then on.
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut signal) => { signal.recv().await; },
+      Err(_) => std::future::pending().await
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => (),
+    _ = terminate => ()
+  }
 }
 
-async fn handle_connection(ws: warp::ws::Ws, server: Server) -> std::result::Result<impl Reply, Rejection> {
+// the first subprotocol offered that names a known encoding wins
+async fn handle_connection(session: String, ws: warp::ws::Ws, protocols: Option<String>, sessions: Sessions) -> std::result::Result<impl Reply, Rejection> {
+  if !Sessions::is_valid_name(&session) {
+    return Err(warp::reject::not_found());
+  }
+  let server = match sessions.get_or_create(&session).await {
+    Some(server) => server,
+    None => return Ok(warp::reply::with_status("too many sessions", StatusCode::SERVICE_UNAVAILABLE).into_response())
+  };
+  let client_id = Uuid::new_v4().simple().to_string();
+  let admin_token = sessions.get_admin_token();
+  let span = info_span!("client", %client_id, %session);
+  let encoding = protocols.unwrap_or_default()
+    .split(',')
+    .find_map(Encoding::from_subprotocol)
+    .unwrap_or(Encoding::Json);
   let mut resp = ws
-    .on_upgrade(|websocket| add_client(websocket, server))
+    .on_upgrade(move |websocket| add_client(websocket, client_id, encoding, admin_token, server).instrument(span))
     .into_response();
-  resp.headers_mut().append("Sec-WebSocket-Protocol", "web_server".parse().unwrap());
+  resp.headers_mut().append("Sec-WebSocket-Protocol", encoding.subprotocol().parse().unwrap());
   Ok(resp)
 }
 
-async fn add_client(websocket: WebSocket, server: Server) {
-  println!("establishing client connection...");
-  let (ws_sink, mut ws_stream) = websocket.split();
-  let (sink, stream) = mpsc::unbounded_channel();
-  let stream = UnboundedReceiverStream::new(stream);
-  tokio::task::spawn(stream.forward(ws_sink).map(|result| {
-    if let Err(e) = result {
-      println!("error sending websocket msg: {}", e);
-    }
-  }));
-  let uuid = Uuid::new_v4().simple().to_string();
-  let new_client = Client {
-    client_id: uuid.clone(),
-    sender: Some(sink),
-  };
-  server.lock().await.clients.insert(uuid.clone(), new_client);
-  while let Some(result) = ws_stream.next().await {
-    let msg = match result {
-      Ok(msg) => msg,
-      Err(e) => {
-        println!("error receiving message for id {}): {}", uuid.clone(), e);
+// the default session's metrics and the number of sessions
+async fn handle_metrics(sessions: Sessions) -> std::result::Result<impl Reply, Rejection> {
+  let server = sessions.get_default();
+  let mut out = Exposition::new();
+  out.single("transit_sessions", "gauge", "Simulation sessions, including the default one.", sessions.count().await as f64);
+  server.get_client_metrics(&mut out);
+  let body = server.call(|s| s.get_prometheus_metrics()).await.unwrap_or_default() + &out.finish();
+  Ok(warp::reply::with_header(body, "Content-Type", "text/plain; version=0.0.4"))
+}
+
+// Reads the client's messages and passes on what the simulation publishes,
+// neither waits on the simulation itself.
+async fn add_client(websocket: WebSocket, client_id: String, encoding: Encoding, admin_token: Option<String>, server: Server) {
+  info!(encoding = encoding.name(), "client connected");
+  let (mut ws_sink, mut ws_stream) = websocket.split();
+  let outbox = Arc::new(Outbox::new());
+  let writer = outbox.clone();
+  let writing = tokio::task::spawn(async move {
+    while let Some(frame) = writer.next().await {
+      if let Err(e) = ws_sink.send(frame).await {
+        warn!("error sending websocket msg: {}", e);
+        writer.close();
         break;
       }
-    };
-    if let Ok(message) = msg.to_str() {
-      server.lock().await.recieve_message(message);
+    }
+    let _ = ws_sink.close().await;
+  }.in_current_span());
+  let mut client = Client::new(client_id.clone(), outbox.clone(), encoding, admin_token);
+  let (posts, mut posted) = mpsc::unbounded_channel();
+  server.add_client(&client_id, ClientHandle { outbox: outbox.clone(), posts });
+  let mut events = server.subscribe();
+  client.welcome(server.call(|s| s.get_time()).await.unwrap_or_default());
+  loop {
+    tokio::select! {
+      result = ws_stream.next() => match result {
+        Some(Ok(msg)) => client.recieve(&msg, &server),
+        Some(Err(e)) => {
+          warn!("error receiving message: {}", e);
+          break;
+        },
+        None => break
+      },
+      Some(data) = posted.recv() => client.handle(data, &server),
+      event = events.recv() => match event {
+        Ok(outgoing) => {
+          if let Err(e) = client.send(&outgoing) {
+            warn!("disconnecting client: {}", e);
+            break;
+          }
+          if outgoing.is_final() { break; }
+        },
+        Err(RecvError::Lagged(missed)) => {
+          warn!(missed, "disconnecting client: missed events");
+          break;
+        },
+        Err(RecvError::Closed) => break
+      }
     }
   }
-  server.lock().await.clients.remove(&uuid);
-  println!("client disconnected");
+  outbox.close();
+  // what is already queued still goes out, the shutdown event among it
+  let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, writing).await;
+  server.remove_client(&client_id);
+  info!("client disconnected");
 }
@@ -1,4 +1,6 @@
-#[derive(Debug, Copy, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Vector3 {
   pub x: f64,
   pub y: f64,
@@ -63,7 +65,7 @@ impl std::ops::Mul<Vector3> for Vector3 {
 impl Vector3 {
   pub fn origin() -> Self { Vector3 { x: 0.0, y: 0.0, z: 0.0 } }
   pub fn new(a: f64, b: f64, c: f64) -> Self { Vector3 { x: a, y: b, z: c } }
-  pub fn from_vec(v: &Vec<f64>) -> Self { Vector3 { x: v[0], y: v[1], z: v[2] } }
+  pub fn from_vec(v: &[f64]) -> Self { Vector3 { x: v[0], y: v[1], z: v[2] } }
   pub fn magnitude(&self) -> f64 { f64::sqrt(*self * *self) }
   pub fn distance(&self, v: &Self) -> f64 { (*self - *v).magnitude() }
   pub fn unit(&self) -> Vector3 { if self.magnitude() == 0.0 {*self} else {*self / self.magnitude()}}
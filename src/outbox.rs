@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+use crate::encoding::Encoding;
+use crate::subscription::Subscription;
+use crate::transit::outgoing::Outgoing;
+
+// frames a client may have waiting before updates start being dropped
+const CAPACITY: usize = 64;
+// reliable events are never dropped, a client this far behind is cut off
+const RELIABLE_LIMIT: usize = 1024;
+// as is a client whose queue stays full this long
+const LAG_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Queued {
+  Frame(Message),
+  // encoded when written, so that it can still be dropped or merged
+  Update(Arc<Outgoing>, Encoding, Arc<Subscription>)
+}
+
+#[derive(Default)]
+struct State {
+  queue: VecDeque<Queued>,
+  dropped: u64,
+  full_since: Option<Instant>,
+  closed: bool
+}
+
+impl State {
+  // Drops the oldest update that a newer one of the same kind is queued
+  // behind, carrying its changes over. Returns false when there is none.
+  fn drop_oldest_update(&mut self) -> bool {
+    let updates = self.queue.iter().enumerate()
+      .filter_map(|(i, q)| match q {
+        Queued::Update(outgoing, ..) => Some((i, outgoing)),
+        Queued::Frame(_) => None
+      })
+      .collect::<Vec<(usize, &Arc<Outgoing>)>>();
+    let pair = updates.iter().enumerate().find_map(|(n, (oldest, older))| {
+      updates[n + 1..].iter().find(|(_, newer)| older.is_same_kind(newer)).map(|(next, newer)| (*oldest, *next, Outgoing::merge(older, newer)))
+    });
+    let (oldest, next, merged) = match pair {
+      Some(pair) => pair,
+      None => return false
+    };
+    if let (Some(merged), Some(Queued::Update(newer, ..))) = (merged, self.queue.get_mut(next)) {
+      *newer = Arc::new(merged);
+    }
+    self.queue.remove(oldest);
+    self.dropped += 1;
+    true
+  }
+}
+
+// The frames waiting to be written to one client's socket. Pushing never
+// waits on the client, it fails instead once the client is too far behind.
+#[derive(Default)]
+pub struct Outbox {
+  state: Mutex<State>,
+  ready: Notify
+}
+
+impl Outbox {
+  pub fn new() -> Self { Outbox::default() }
+  pub fn push_frame(&self, frame: Message) -> Result<(), String> {
+    self.push(Queued::Frame(frame))
+  }
+  pub fn push_update(&self, outgoing: Arc<Outgoing>, encoding: Encoding, subscription: Arc<Subscription>) -> Result<(), String> {
+    self.push(Queued::Update(outgoing, encoding, subscription))
+  }
+  fn push(&self, item: Queued) -> Result<(), String> {
+    let mut state = self.state.lock().unwrap();
+    if state.closed { return Err("connection closed".to_string()); }
+    state.queue.push_back(item);
+    while state.queue.len() > CAPACITY && state.drop_oldest_update() {}
+    let len = state.queue.len();
+    if len > RELIABLE_LIMIT {
+      return Err(format!("{} events waiting", len));
+    }
+    if len >= CAPACITY {
+      let since = *state.full_since.get_or_insert_with(Instant::now);
+      if since.elapsed() > LAG_TIMEOUT {
+        return Err(format!("behind for {:.0} seconds", since.elapsed().as_secs_f64()));
+      }
+    } else {
+      state.full_since = None;
+    }
+    drop(state);
+    self.ready.notify_one();
+    Ok(())
+  }
+  // the next frame to write, None once closed
+  pub async fn next(&self) -> Option<Message> {
+    loop {
+      match self.pop() {
+        Some(Queued::Frame(frame)) => return Some(frame),
+        Some(Queued::Update(outgoing, encoding, subscription)) => if let Some(frame) = outgoing.frame(encoding, &subscription) {
+          return Some(frame);
+        },
+        None if self.state.lock().unwrap().closed => return None,
+        None => self.ready.notified().await
+      }
+    }
+  }
+  fn pop(&self) -> Option<Queued> {
+    let mut state = self.state.lock().unwrap();
+    let item = state.queue.pop_front();
+    if state.queue.len() < CAPACITY {
+      state.full_since = None;
+    }
+    item
+  }
+  pub fn close(&self) {
+    self.state.lock().unwrap().closed = true;
+    self.ready.notify_one();
+  }
+  pub fn get_length(&self) -> usize {
+    self.state.lock().unwrap().queue.len()
+  }
+  pub fn get_dropped(&self) -> u64 {
+    self.state.lock().unwrap().dropped
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+  use std::collections::HashMap;
+
+  fn update(id: i32, x: f64) -> Arc<Outgoing> {
+    Arc::new(Outgoing::new("WorldUpdate", &json!({ "time": x, "entities": [{ "id": id, "pos": [x, 0, 0] }] }), HashMap::new()))
+  }
+
+  fn push_update(outbox: &Outbox, outgoing: Arc<Outgoing>) -> Result<(), String> {
+    outbox.push_update(outgoing, Encoding::Json, Arc::default())
+  }
+
+  // what is queued, in order, as the client would get it
+  fn drain(outbox: &Outbox) -> Vec<Value> {
+    std::iter::from_fn(|| outbox.pop())
+      .filter_map(|q| match q {
+        Queued::Frame(frame) => Some(frame),
+        Queued::Update(outgoing, encoding, subscription) => outgoing.frame(encoding, &subscription)
+      })
+      .map(|frame| serde_json::from_str(frame.to_str().unwrap()).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn overflowing_updates_are_merged_into_newer_ones() {
+    let outbox = Outbox::new();
+    for n in 0..CAPACITY + 10 {
+      push_update(&outbox, update(n as i32 % 2, n as f64)).unwrap();
+    }
+    assert_eq!((outbox.get_length(), outbox.get_dropped()), (CAPACITY, 10));
+    let updates = drain(&outbox);
+    assert_eq!(updates[0]["details"]["time"], 10.);
+    let first = updates[0]["details"]["entities"].as_array().unwrap();
+    assert_eq!(first.iter().map(|e| (e["id"].clone(), e["pos"][0].clone())).collect::<Vec<(Value, Value)>>(),
+      [(json!(0), json!(10.)), (json!(1), json!(9.))]);
+  }
+
+  #[test]
+  fn reliable_frames_are_kept_until_the_limit() {
+    let outbox = Outbox::new();
+    for n in 0..CAPACITY {
+      outbox.push_frame(Message::text(json!({ "event": "Frame", "details": n }).to_string())).unwrap();
+    }
+    for n in 0..3 {
+      push_update(&outbox, update(0, n as f64)).unwrap();
+    }
+    assert_eq!((outbox.get_length(), outbox.get_dropped()), (CAPACITY + 1, 2));
+    let queued = drain(&outbox);
+    assert_eq!(queued.iter().filter(|q| q["event"] == "Frame").count(), CAPACITY);
+    assert_eq!(queued.last().unwrap()["details"]["entities"][0]["pos"][0], 2.);
+    for _ in 0..RELIABLE_LIMIT {
+      outbox.push_frame(Message::text("{}")).unwrap();
+    }
+    assert_eq!(outbox.push_frame(Message::text("{}")), Err(format!("{} events waiting", RELIABLE_LIMIT + 1)));
+  }
+
+  #[test]
+  fn clients_full_for_too_long_are_cut_off() {
+    let outbox = Outbox::new();
+    for _ in 0..CAPACITY {
+      outbox.push_frame(Message::text("{}")).unwrap();
+    }
+    assert!(outbox.state.lock().unwrap().full_since.is_some());
+    outbox.push_frame(Message::text("{}")).unwrap();
+    outbox.state.lock().unwrap().full_since = Some(Instant::now() - LAG_TIMEOUT - Duration::from_secs(1));
+    assert!(outbox.push_frame(Message::text("{}")).is_err_and(|e| e.starts_with("behind for 11")));
+    drain(&outbox);
+    outbox.push_frame(Message::text("{}")).unwrap();
+    assert!(outbox.state.lock().unwrap().full_since.is_none());
+  }
+}
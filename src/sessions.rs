@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::Server;
+use crate::client::ClientHandle;
+use crate::config::Config;
+use crate::transit::simulation_task::SimulationHandle;
+use crate::transit::transit_service::TransitServer;
+
+// the session plain `/` connections, the http api and the cli flags use
+pub const DEFAULT_SESSION: &str = "default";
+// sessions without clients for this long are dropped, checked every sweep
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Session {
+  server: Server,
+  idle_since: Option<Instant>
+}
+
+// Named simulations, each with its own model, clock and clients. A session is
+// created by the first connection asking for it, up to `max_sessions` of them.
+#[derive(Clone)]
+pub struct Sessions {
+  sessions: Arc<Mutex<HashMap<String, Session>>>,
+  default: Server,
+  // what new sessions are set up from
+  config: Arc<Config>
+}
+
+impl Sessions {
+  pub fn new(default: Server, config: &Config) -> Self {
+    let mut sessions = HashMap::new();
+    sessions.insert(DEFAULT_SESSION.to_string(), Session { server: default.clone(), idle_since: None });
+    Sessions { sessions: Arc::new(Mutex::new(sessions)), default, config: Arc::new(config.clone()) }
+  }
+  // names are kept to something that reads well in a url
+  pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+  }
+  pub fn get_admin_token(&self) -> Option<String> {
+    self.config.admin_token.clone()
+  }
+  pub fn get_default(&self) -> Server {
+    self.default.clone()
+  }
+  // the named session, started if there is room for another one
+  pub async fn get_or_create(&self, name: &str) -> Option<Server> {
+    let mut sessions = self.sessions.lock().await;
+    if !sessions.contains_key(name) && sessions.len() >= self.config.max_sessions {
+      warn!(session = %name, limit = self.config.max_sessions, "session refused, too many sessions");
+      return None;
+    }
+    let session = sessions.entry(name.to_string()).or_insert_with(|| {
+      info!(session = %name, "creating session");
+      let server = SimulationHandle::spawn(name, TransitServer::new(&self.config), self.config.tick_rate);
+      Session { server, idle_since: None }
+    });
+    session.idle_since = None;
+    Some(session.server.clone())
+  }
+  // the client with this id, in whichever session it is
+  pub async fn find_client(&self, client_id: &str) -> Option<ClientHandle> {
+    self.sessions.lock().await.values().find_map(|s| s.server.get_client(client_id))
+  }
+  pub async fn count(&self) -> usize {
+    self.sessions.lock().await.len()
+  }
+  pub async fn get_client_count(&self) -> usize {
+    self.sessions.lock().await.values().map(|s| s.server.get_client_count()).sum()
+  }
+  // sends every session's clients the shutdown event and closes its event log
+  pub async fn shutdown(&self) {
+    let servers = self.sessions.lock().await.values().map(|s| s.server.clone()).collect::<Vec<Server>>();
+    for server in servers {
+      server.call(|s| s.shutdown()).await;
+    }
+  }
+  // Drops sessions that have had no clients for IDLE_TIMEOUT, the default
+  // session is kept for the lifetime of the server.
+  async fn reap(&self) {
+    let now = Instant::now();
+    let mut sessions = self.sessions.lock().await;
+    for (name, session) in sessions.iter_mut() {
+      if name == DEFAULT_SESSION { continue; }
+      if session.server.get_client_count() == 0 {
+        session.idle_since.get_or_insert(now);
+      } else {
+        session.idle_since = None;
+      }
+    }
+    sessions.retain(|name, session| {
+      let idle = session.idle_since.is_some_and(|t| now.duration_since(t) >= IDLE_TIMEOUT);
+      if idle { info!(session = %name, "reaping idle session"); }
+      !idle
+    });
+  }
+  pub fn spawn_reaper(&self) {
+    let sessions = self.clone();
+    tokio::task::spawn(async move {
+      let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+      loop {
+        interval.tick().await;
+        sessions.reap().await;
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn sessions(max_sessions: usize) -> Sessions {
+    let config = Config { max_sessions, ..Config::default() };
+    let default = SimulationHandle::spawn(DEFAULT_SESSION, TransitServer::new(&config), None);
+    Sessions::new(default, &config)
+  }
+
+  #[tokio::test]
+  async fn sessions_keep_their_own_world() {
+    let sessions = sessions(3);
+    let (a, b) = (sessions.get_or_create("a").await.unwrap(), sessions.get_or_create("b").await.unwrap());
+    a.call(|s| s.execute(json!({ "command": "CreateEntity", "type": "robot", "name": "Alice", "position": [0, 264, 0] }))).await;
+    a.call(|s| s.execute(json!({ "command": "Update", "dt": 0.05 }))).await;
+    assert_eq!(a.call(|s| (s.get_entities().len(), s.get_time())).await, Some((1, 0.05)));
+    assert_eq!(b.call(|s| (s.get_entities().len(), s.get_time())).await, Some((0, 0.)));
+    assert_eq!(sessions.get_default().call(|s| s.get_entities().len()).await, Some(0));
+    let again = sessions.get_or_create("a").await.unwrap();
+    assert_eq!(again.call(|s| s.get_entities().len()).await, Some(1));
+  }
+
+  #[tokio::test]
+  async fn refuses_sessions_past_the_limit() {
+    let sessions = sessions(2);
+    assert!(sessions.get_or_create("a").await.is_some());
+    assert!(sessions.get_or_create("b").await.is_none());
+    assert!(sessions.get_or_create("a").await.is_some());
+    assert!(sessions.get_or_create(DEFAULT_SESSION).await.is_some());
+    assert_eq!(sessions.count().await, 2);
+    sessions.sessions.lock().await.get_mut("a").unwrap().idle_since = Some(Instant::now() - IDLE_TIMEOUT);
+    sessions.reap().await;
+    assert_eq!(sessions.count().await, 1);
+    assert!(sessions.get_or_create("b").await.is_some());
+  }
+
+  #[test]
+  fn names_read_well_in_a_url() {
+    for name in ["a", "team-1", "run_2"] {
+      assert!(Sessions::is_valid_name(name), "{}", name);
+    }
+    for name in ["", "a/b", "caf\u{e9}", &"x".repeat(65)] {
+      assert!(!Sessions::is_valid_name(name), "{}", name);
+    }
+  }
+}
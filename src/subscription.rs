@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+use serde_json::{json, Value};
+
+use crate::math::vector3::Vector3;
+use crate::transit::world::WorldBounds;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Topic {
+  Entities,
+  Trips,
+  Traces,
+  Metrics,
+  Notifications
+}
+
+impl Topic {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "entities" => Some(Topic::Entities),
+      "trips" => Some(Topic::Trips),
+      "traces" => Some(Topic::Traces),
+      "metrics" => Some(Topic::Metrics),
+      "notifications" => Some(Topic::Notifications),
+      _ => None
+    }
+  }
+  pub fn name(&self) -> &'static str {
+    match self {
+      Topic::Entities => "entities",
+      Topic::Trips => "trips",
+      Topic::Traces => "traces",
+      Topic::Metrics => "metrics",
+      Topic::Notifications => "notifications"
+    }
+  }
+  // events outside every topic are always delivered
+  pub fn of(event: &str) -> Option<Self> {
+    match event {
+      "AddEntity" | "RemoveEntity" | "UpdateEntity" | "WorldUpdate" => Some(Topic::Entities),
+      "TripScheduled" | "TripStateChanged" | "TripEtas" | "TripCancelled" | "TripReassigned" => Some(Topic::Trips),
+      "observe" => Some(Topic::Traces),
+      "Metrics" | "MetricsExported" => Some(Topic::Metrics),
+      "Notification" => Some(Topic::Notifications),
+      _ => None
+    }
+  }
+}
+
+// What a client wants to hear about. Without topics everything is sent,
+// `types` and `area` narrow entity events down to some kinds of entity and
+// to poses inside a box.
+#[derive(Debug, Clone, Default)]
+pub struct Subscription {
+  topics: Option<BTreeSet<Topic>>,
+  types: Option<BTreeSet<String>>,
+  area: Option<WorldBounds>
+}
+
+impl Subscription {
+  pub fn from_json(data: &Value) -> Result<Self, String> {
+    let topics = match data["topics"].as_array() {
+      Some(names) => Some(names.iter()
+        .map(|n| n.as_str().and_then(Topic::from_name).ok_or(format!("unknown topic {}", n)))
+        .collect::<Result<BTreeSet<Topic>, String>>()?),
+      None => None
+    };
+    let types = data["types"].as_array()
+      .map(|t| t.iter().filter_map(|v| v.as_str().map(String::from)).collect());
+    let area = match &data["area"] {
+      Value::Null => None,
+      area => Some(WorldBounds::from_json(area).ok_or("area needs min and max as [x, y, z]")?)
+    };
+    Ok(Subscription { topics, types, area })
+  }
+  pub fn to_json(&self) -> Value {
+    json!({
+      "topics": self.topics.as_ref().map(|t| t.iter().map(|t| t.name()).collect::<Vec<&str>>()),
+      "types": self.types,
+      "area": self.area.map(|a| a.to_json())
+    })
+  }
+  pub fn wants(&self, event: &str) -> bool {
+    match (Topic::of(event), &self.topics) {
+      (Some(topic), Some(topics)) => topics.contains(&topic),
+      _ => true
+    }
+  }
+  pub fn is_filtered(&self) -> bool {
+    self.types.is_some() || self.area.is_some()
+  }
+  pub fn wants_type(&self, kind: &str) -> bool {
+    self.types.as_ref().is_none_or(|t| t.contains(kind))
+  }
+  pub fn wants_entity(&self, kind: &str, pos: Vector3) -> bool {
+    self.wants_type(kind) && self.area.is_none_or(|a| a.contains(pos))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn topics_pick_events() {
+    let subscription = Subscription::from_json(&json!({ "topics": ["trips", "metrics"] })).unwrap();
+    for (event, wanted) in [("TripEtas", true), ("Metrics", true), ("WorldUpdate", false), ("observe", false), ("Welcome", true)] {
+      assert_eq!(subscription.wants(event), wanted, "{}", event);
+    }
+    assert!(Subscription::default().wants("observe"));
+    assert!(!subscription.is_filtered());
+  }
+
+  #[test]
+  fn types_and_area_pick_entities() {
+    let subscription = Subscription::from_json(&json!({
+      "types": ["drone"],
+      "area": { "min": [0, 0, 0], "max": [100, 300, 100] }
+    })).unwrap();
+    assert!(subscription.is_filtered());
+    assert!(subscription.wants_entity("drone", Vector3::new(50., 264., 50.)));
+    assert!(!subscription.wants_entity("drone", Vector3::new(150., 264., 50.)));
+    assert!(!subscription.wants_entity("robot", Vector3::new(50., 264., 50.)));
+    assert_eq!(Subscription::from_json(&subscription.to_json()).unwrap().to_json(), subscription.to_json());
+  }
+
+  #[test]
+  fn rejects_what_it_cannot_read() {
+    assert!(Subscription::from_json(&json!({ "topics": ["weather"] })).is_err());
+    assert!(Subscription::from_json(&json!({ "area": { "min": [0, 0] } })).is_err());
+  }
+}
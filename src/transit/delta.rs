@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, HashMap};
+use serde_json::{json, Value};
+
+use crate::math::vector3::Vector3;
+
+use super::entities::entity::{Entity, EntityTrait};
+
+// smaller moves than these are not worth sending
+const POSITION_THRESHOLD: f64 = 0.01;
+const DIRECTION_THRESHOLD: f64 = 0.001;
+
+struct Sent {
+  pos: Vector3,
+  dir: Vector3,
+  details: Value
+}
+
+impl Sent {
+  fn of(entity: &Entity) -> Self {
+    Sent {
+      pos: entity.get_position(),
+      dir: entity.get_direction(),
+      details: entity.get_details().clone()
+    }
+  }
+}
+
+// Everything a WorldUpdate can say about an entity, for a client that has
+// missed some of its changes.
+pub fn full_change(id: i32, entity: &Entity) -> Value {
+  let (pos, dir) = (entity.get_position(), entity.get_direction());
+  json!({
+    "id": id,
+    "pos": [pos.x, pos.y, pos.z],
+    "dir": [dir.x, dir.y, dir.z],
+    "details": entity.get_details()
+  })
+}
+
+// Remembers what clients were last told about each entity so that a tick
+// only carries what changed since.
+#[derive(Default)]
+pub struct DeltaEncoder {
+  sent: HashMap<i32, Sent>
+}
+
+impl DeltaEncoder {
+  pub fn new() -> Self { DeltaEncoder::default() }
+  // Starts over from `entities` as clients were just sent them in full, by
+  // AddEntity events or a snapshot.
+  pub fn reset(&mut self, entities: &BTreeMap<i32, Entity>) {
+    self.sent = entities.iter().map(|(id, entity)| (*id, Sent::of(entity))).collect();
+  }
+  // an entity clients were sent in full by an AddEntity event
+  pub fn add(&mut self, id: i32, entity: &Entity) {
+    self.sent.insert(id, Sent::of(entity));
+  }
+  // where clients last saw each entity
+  pub fn get_positions(&self) -> HashMap<i32, Vector3> {
+    self.sent.iter().map(|(id, sent)| (*id, sent.pos)).collect()
+  }
+  // Entities whose pose moved past the thresholds get `pos` and `dir`,
+  // `details` are only included when they differ from what was sent before.
+  // Colors go out as updateDetails notifications instead. Unchanged entities are left out, ones clients have not
+  // been sent yet get everything.
+  pub fn encode(&mut self, entities: &BTreeMap<i32, Entity>) -> Vec<Value> {
+    self.sent.retain(|id, _| entities.contains_key(id));
+    let mut changes = vec![];
+    for (id, entity) in entities.iter() {
+      let sent = match self.sent.get_mut(id) {
+        Some(sent) => sent,
+        None => {
+          self.sent.insert(*id, Sent::of(entity));
+          changes.push(full_change(*id, entity));
+          continue;
+        }
+      };
+      let (pos, dir, details) = (entity.get_position(), entity.get_direction(), entity.get_details());
+      let mut change = json!({ "id": id });
+      if sent.pos.distance(&pos) > POSITION_THRESHOLD || sent.dir.distance(&dir) > DIRECTION_THRESHOLD {
+        change["pos"] = json!([pos.x, pos.y, pos.z]);
+        change["dir"] = json!([dir.x, dir.y, dir.z]);
+        sent.pos = pos;
+        sent.dir = dir;
+      }
+      if &sent.details != details {
+        change["details"] = details.clone();
+        sent.details = details.clone();
+      }
+      if change.as_object().is_some_and(|c| c.len() > 1) {
+        changes.push(change);
+      }
+    }
+    changes
+  }
+}
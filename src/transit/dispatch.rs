@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+
+use crate::graph::graph::path_length;
+use crate::math::vector3::Vector3;
+
+use super::trip::TripMode;
+
+// a pooled passenger may add at most this multiple of its own trip length to a route
+const MAX_DETOUR: f64 = 1.5;
+// a mixed trip hands the robot back to the ground network this far from its destination
+const LAST_MILE: f64 = 150.;
+
+// How requested trips are handed to idle drones. Pooled lets each drone in
+// turn take the requests closest to it, sharing rides where the detour allows.
+// Nearest gives the oldest request the closest drone, one trip per drone.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum DispatchPolicy {
+  #[default]
+  Pooled,
+  Nearest
+}
+
+impl DispatchPolicy {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "pooled" => Some(DispatchPolicy::Pooled),
+      "nearest" => Some(DispatchPolicy::Nearest),
+      _ => None
+    }
+  }
+  pub fn name(&self) -> &'static str {
+    match self {
+      DispatchPolicy::Pooled => "pooled",
+      DispatchPolicy::Nearest => "nearest"
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StopKind {
+  Pickup,
+  Dropoff
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stop {
+  pub trip_id: i32,
+  pub kind: StopKind,
+  pub position: Vector3,
+  pub weight: f64,
+  pub strategy: String,
+  pub estimate: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+  pub trip_id: i32,
+  pub pickup: Vector3,
+  pub dropoff: Vector3,
+  pub weight: f64,
+  pub strategy: String
+}
+impl Request {
+  fn stop(&self, kind: StopKind) -> Stop {
+    Stop {
+      trip_id: self.trip_id,
+      kind,
+      position: match kind {
+        StopKind::Pickup => self.pickup,
+        StopKind::Dropoff => self.dropoff
+      },
+      weight: self.weight,
+      strategy: self.strategy.clone(),
+      estimate: 0.
+    }
+  }
+}
+
+pub fn route_length(start: Vector3, stops: &[Stop]) -> f64 {
+  let mut prev = start;
+  let mut length = 0.;
+  for s in stops {
+    length += prev.distance(&s.position);
+    prev = s.position;
+  }
+  length
+}
+
+fn fits(stops: &[Stop], capacity: usize, max_payload: f64) -> bool {
+  let (mut count, mut weight) = (0, 0.);
+  for s in stops {
+    match s.kind {
+      StopKind::Pickup => { count += 1; weight += s.weight; },
+      StopKind::Dropoff => { count -= 1; weight -= s.weight; }
+    }
+    if count > capacity || weight > max_payload { return false; }
+  }
+  true
+}
+
+// Builds a route for a carrier at `start`. Requests are considered in order,
+// the first one that fits is always taken and later ones are pooled in by
+// cheapest insertion as long as their detour stays acceptable.
+pub fn plan_route(start: Vector3, requests: &[Request], capacity: usize, max_payload: f64) -> Vec<Stop> {
+  let mut stops: Vec<Stop> = vec![];
+  let mut passengers = 0;
+  for r in requests {
+    if passengers >= capacity { break; }
+    if r.weight > max_payload { continue; }
+    if stops.is_empty() {
+      stops = vec![r.stop(StopKind::Pickup), r.stop(StopKind::Dropoff)];
+      passengers += 1;
+      continue;
+    }
+    let base = route_length(start, &stops);
+    let mut best: Option<(f64, Vec<Stop>)> = None;
+    for i in 0..=stops.len() {
+      for j in i..=stops.len() {
+        let mut candidate = stops.clone();
+        candidate.insert(i, r.stop(StopKind::Pickup));
+        candidate.insert(j + 1, r.stop(StopKind::Dropoff));
+        if !fits(&candidate, capacity, max_payload) { continue; }
+        let cost = route_length(start, &candidate) - base;
+        if best.as_ref().is_none_or(|(c, _)| cost < *c) {
+          best = Some((cost, candidate));
+        }
+      }
+    }
+    if let Some((cost, candidate)) = best {
+      if cost <= MAX_DETOUR * r.pickup.distance(&r.dropoff) {
+        stops = candidate;
+        passengers += 1;
+      }
+    }
+  }
+  stops
+}
+
+#[derive(Debug, Clone)]
+pub struct ModePlan {
+  pub mode: TripMode,
+  pub time: f64,
+  pub rendezvous: usize,
+  pub handoff: usize
+}
+
+// Picks how a robot travels along its ground path. `carrier` is the position
+// and speed of the drone expected to serve it and `flight` estimates the
+// flying time between two points. With no mode given the fastest one wins.
+// `ground` must hold at least one node.
+pub fn plan_mode(mode: Option<TripMode>, ground: &[Vector3], start: Vector3, robot_speed: f64,
+  carrier: Option<(Vector3, f64)>, flight: impl Fn(Vector3, Vector3) -> f64) -> ModePlan {
+  let last = ground.len() - 1;
+  let drive_to = |i: usize| if robot_speed > 0. {
+    (start.distance(&ground[0]) + path_length(&ground[..=i])) / robot_speed
+  } else { f64::INFINITY };
+  let drive_from = |i: usize| if robot_speed > 0. { path_length(&ground[i..]) / robot_speed } else { f64::INFINITY };
+  let fly_to = |p: Vector3| match carrier {
+    Some((pos, speed)) if speed > 0. => pos.distance(&p) / speed,
+    _ => f64::INFINITY
+  };
+  let drive = || ModePlan { mode: TripMode::Drive, time: drive_to(last), rendezvous: last, handoff: last };
+  let fly = || ModePlan { mode: TripMode::Fly, time: fly_to(start) + flight(start, ground[last]), rendezvous: 0, handoff: last };
+  let mixed = || {
+    let rendezvous = match carrier {
+      Some(_) => (0..=last).find(|&i| drive_to(i) >= fly_to(ground[i])).unwrap_or(last),
+      None => 0
+    };
+    let handoff = (rendezvous..=last).find(|&k| path_length(&ground[k..]) <= LAST_MILE).unwrap_or(last);
+    ModePlan {
+      mode: TripMode::Mixed,
+      time: drive_to(rendezvous).max(fly_to(ground[rendezvous]))
+        + flight(ground[rendezvous], ground[handoff]) + drive_from(handoff),
+      rendezvous, handoff
+    }
+  };
+  match mode {
+    Some(TripMode::Drive) => drive(),
+    Some(TripMode::Fly) => fly(),
+    Some(TripMode::Mixed) => mixed(),
+    None => [drive(), fly(), mixed()].into_iter()
+      .min_by(|a, b| a.time.total_cmp(&b.time))
+      .unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn point(x: f64) -> Vector3 { Vector3::new(x, 0., 0.) }
+
+  fn request(trip_id: i32, pickup: f64, dropoff: f64, weight: f64) -> Request {
+    Request { trip_id, pickup: point(pickup), dropoff: point(dropoff), weight, strategy: "astar".to_string() }
+  }
+
+  fn stops(route: &[Stop]) -> Vec<(i32, StopKind)> {
+    route.iter().map(|s| (s.trip_id, s.kind)).collect()
+  }
+
+  #[test]
+  fn fits_checks_count_and_weight() {
+    let (a, b) = (request(1, 0., 100., 60.), request(2, 10., 90., 60.));
+    let nested = [a.stop(StopKind::Pickup), b.stop(StopKind::Pickup), b.stop(StopKind::Dropoff), a.stop(StopKind::Dropoff)];
+    let sequential = [a.stop(StopKind::Pickup), a.stop(StopKind::Dropoff), b.stop(StopKind::Pickup), b.stop(StopKind::Dropoff)];
+    assert!(fits(&nested, 2, 120.));
+    assert!(!fits(&nested, 1, 120.));
+    assert!(!fits(&nested, 2, 100.));
+    assert!(fits(&sequential, 1, 60.));
+  }
+
+  #[test]
+  fn pools_a_passenger_on_the_way() {
+    let route = plan_route(point(0.), &[request(1, 0., 100., 10.), request(2, 10., 90., 10.)], 2, 100.);
+    assert_eq!(stops(&route), vec![
+      (1, StopKind::Pickup), (2, StopKind::Pickup), (2, StopKind::Dropoff), (1, StopKind::Dropoff)
+    ]);
+  }
+
+  #[test]
+  fn respects_capacity_by_count() {
+    let route = plan_route(point(0.), &[request(1, 0., 100., 10.), request(2, 10., 90., 10.)], 1, 100.);
+    assert_eq!(stops(&route), vec![(1, StopKind::Pickup), (1, StopKind::Dropoff)]);
+  }
+
+  #[test]
+  fn respects_capacity_by_weight() {
+    // together they are too heavy and flying them one after the other is too long a detour
+    let route = plan_route(point(0.), &[request(1, 0., 100., 60.), request(2, 10., 90., 60.)], 2, 100.);
+    assert_eq!(stops(&route), vec![(1, StopKind::Pickup), (1, StopKind::Dropoff)]);
+    // a passenger heavier than the payload is skipped, not the ones after it
+    let route = plan_route(point(0.), &[request(1, 0., 100., 150.), request(2, 10., 90., 60.)], 2, 100.);
+    assert_eq!(stops(&route), vec![(2, StopKind::Pickup), (2, StopKind::Dropoff)]);
+  }
+
+  #[test]
+  fn picks_up_before_dropping_off() {
+    let requests = [
+      request(1, 0., 100., 10.),
+      request(2, 90., 5., 10.),
+      request(3, 50., 60., 10.),
+      request(4, 95., 20., 10.)
+    ];
+    let route = plan_route(point(0.), &requests, 3, 100.);
+    assert!(route.len() > 2);
+    for r in requests.iter() {
+      let pickup = route.iter().position(|s| s.trip_id == r.trip_id && s.kind == StopKind::Pickup);
+      let dropoff = route.iter().position(|s| s.trip_id == r.trip_id && s.kind == StopKind::Dropoff);
+      match (pickup, dropoff) {
+        (Some(p), Some(d)) => assert!(p < d, "trip {} dropped off before pickup", r.trip_id),
+        (None, None) => (),
+        _ => panic!("trip {} has only one of its stops", r.trip_id)
+      }
+    }
+    assert!(fits(&route, 3, 100.));
+  }
+
+  // road nodes every 100 units along x up to `length`
+  fn road(length: f64) -> Vec<Vector3> {
+    (0..=(length / 100.) as usize).map(|i| point(i as f64 * 100.)).collect()
+  }
+
+  fn flight(a: Vector3, b: Vector3) -> f64 { a.distance(&b) / 10. }
+
+  #[test]
+  fn drives_short_trips() {
+    let plan = plan_mode(None, &road(200.), point(0.), 5., Some((point(1000.), 10.)), flight);
+    assert_eq!(plan.mode, TripMode::Drive);
+    assert_eq!(plan.time, 40.);
+  }
+
+  #[test]
+  fn flies_long_trips() {
+    let plan = plan_mode(None, &road(5000.), point(0.), 5., Some((point(0.), 10.)), flight);
+    assert_eq!(plan.mode, TripMode::Fly);
+    assert_eq!(plan.time, 500.);
+  }
+
+  #[test]
+  fn mixes_when_the_drone_is_ahead() {
+    // driving to meet the drone beats waiting for it and the last stretch is driven
+    let ground = road(5000.);
+    let plan = plan_mode(None, &ground, point(0.), 5., Some((point(2500.), 10.)), flight);
+    assert_eq!(plan.mode, TripMode::Mixed);
+    assert_eq!(ground[plan.rendezvous], point(900.));
+    assert_eq!(ground[plan.handoff], point(4900.));
+    assert_eq!(plan.time, 180. + 400. + 20.);
+  }
+
+  #[test]
+  fn drives_without_a_drone() {
+    let plan = plan_mode(None, &road(5000.), point(0.), 5., None, flight);
+    assert_eq!(plan.mode, TripMode::Drive);
+  }
+
+  #[test]
+  fn keeps_a_requested_mode() {
+    let ground = road(5000.);
+    for mode in [TripMode::Drive, TripMode::Fly, TripMode::Mixed] {
+      assert_eq!(plan_mode(Some(mode), &ground, point(0.), 5., Some((point(0.), 10.)), flight).mode, mode);
+    }
+  }
+}
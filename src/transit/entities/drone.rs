@@ -1,9 +1,12 @@
-use super::entity::EntityTrait;
+use std::collections::VecDeque;
+
+use super::entity::{EntityTrait, restore_vector, save_strategy};
 use crate::graph::graph::Graph;
-use crate::graph::routing::{AStar, DepthFirstSearch, Dijkstras};
 use crate::math::vector3::Vector3;
-use crate::transit::strategy::{MovementStrategy, PathStrategy, SpinDecorator, JumpDecorator};
-use serde_json::Value;
+use crate::transit::dispatch::{Stop, StopKind};
+use crate::transit::strategy::{MovementStrategy, PathStrategy, load_strategy, search_strategy};
+use crate::transit::world::World;
+use serde_json::{json, Value};
 
 pub struct Drone {
   id: i32,
@@ -13,6 +16,9 @@ pub struct Drone {
   destination: Vector3,
   availability: bool,
   speed: f64,
+  capacity: usize,
+  max_payload: f64,
+  pub stops: VecDeque<Stop>,
   pub to_robot: Option<Box<dyn MovementStrategy>>,
   pub to_final_destination: Option<Box<dyn MovementStrategy>>,
 }
@@ -25,10 +31,9 @@ impl Drone {
     let mut h = Drone {
       id,
       details: data.clone(),
-      speed: match data["speed"].as_f64() {
-        Some(s) => s,
-        _ => 10.
-      },
+      speed: data["speed"].as_f64().unwrap_or(10.),
+      capacity: data["capacity"].as_u64().unwrap_or(1) as usize,
+      max_payload: data["max_payload"].as_f64().unwrap_or(f64::INFINITY),
       position: match data["position"].as_array() {
         Some(a) => match a.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>()[..] {
           [Some(x), Some(y), Some(z)] => Vector3::new(x, y, z),
@@ -45,6 +50,7 @@ impl Drone {
       },
       destination: Vector3::origin(),
       availability: true,
+      stops: VecDeque::new(),
       to_robot: None,
       to_final_destination: None
     };
@@ -57,42 +63,59 @@ impl Drone {
   }
   pub fn continue_trip(&mut self, strat: String, dest: Vector3, graph: &Graph) {
     self.destination = dest;
-    self.to_final_destination = match strat.as_str() {
-      "astar" => Some(Box::new(
-        JumpDecorator::new(
-          PathStrategy::from_path(
-            graph.get_path(self.get_position(), dest, 
-              Box::new(AStar::new())
-            ).unwrap()
-          )
-        , 4., 10.)
-      )),
-      "dfs" => Some(Box::new(
-        SpinDecorator::new(
-          JumpDecorator::new(
-            PathStrategy::from_path(
-              graph.get_path(self.get_position(), dest, 
-                Box::new(DepthFirstSearch::new())
-              ).unwrap()
-            )
-          , 4., 10.)
-        , 4.)
-      )),
-      "dijkstra" => Some(Box::new(
-        JumpDecorator::new(
-          SpinDecorator::new(
-            PathStrategy::from_path(
-              graph.get_path(self.get_position(), dest, 
-                Box::new(Dijkstras::new())
-              ).unwrap()
-            )
-          , 4.)
-        , 4., 10.)
-      )),
-      _ => Some(Box::new(PathStrategy::from_start_end(self.get_position(), dest)))
+    self.to_final_destination = Some(search_strategy(&strat, self.get_position(), dest, graph));
+  }
+  pub fn get_capacity(&self) -> usize { self.capacity }
+  pub fn get_max_payload(&self) -> f64 { self.max_payload }
+  pub fn assign_route(&mut self, stops: Vec<Stop>, graph: &Graph) {
+    self.stops = stops.into();
+    self.availability = false;
+    self.start_leg(graph);
+  }
+  pub fn start_leg(&mut self, graph: &Graph) {
+    self.to_robot = None;
+    self.to_final_destination = None;
+    let (kind, position, strategy) = match self.stops.front() {
+      Some(s) => (s.kind, s.position, s.strategy.clone()),
+      None => return
     };
+    match kind {
+      StopKind::Pickup => self.establish_trip(position),
+      StopKind::Dropoff => self.continue_trip(strategy, position, graph)
+    }
+  }
+  pub fn leg_completed(&self) -> bool {
+    self.to_robot.is_none() && self.to_final_destination.is_none()
+  }
+  pub fn remove_stops(&mut self, trip_id: i32, graph: &Graph) {
+    let current = self.stops.front().map(|s| s.trip_id);
+    self.stops.retain(|s| s.trip_id != trip_id);
+    if self.stops.is_empty() {
+      self.finish_trip();
+    } else if current == Some(trip_id) {
+      self.start_leg(graph);
+    }
+  }
+  pub fn get_leg_path(&self) -> Vec<Vector3> {
+    match (&self.to_robot, &self.to_final_destination) {
+      (Some(strat), _) | (None, Some(strat)) => strat.get_path().to_vec(),
+      _ => vec![]
+    }
+  }
+  pub fn get_leg_eta(&self) -> f64 {
+    match (&self.to_robot, &self.to_final_destination) {
+      (Some(strat), _) | (None, Some(strat)) => strat.remaining_time(self.position, self.speed),
+      _ => 0.
+    }
+  }
+  pub fn get_stop_eta(&self, trip_id: i32, kind: StopKind) -> Option<f64> {
+    let index = self.stops.iter().position(|s| s.trip_id == trip_id && s.kind == kind)?;
+    Some(self.get_leg_eta() + self.stops.iter().skip(1).take(index).map(|s| s.estimate).sum::<f64>())
   }
   pub fn finish_trip(&mut self) {
+    self.stops.clear();
+    self.to_robot = None;
+    self.to_final_destination = None;
     self.availability = true;
   }
 }
@@ -103,9 +126,16 @@ impl EntityTrait for Drone {
   fn get_direction(&self) -> Vector3 { self.direction }
   fn get_destination(&self) -> Vector3 { self.destination }
   fn get_availability(&self) -> bool { self.availability }
+  // yellow on the way to a pickup, green with a passenger aboard
+  fn get_color(&self) -> Option<String> {
+    match self.stops.front()?.kind {
+      StopKind::Pickup => Some("#ffff00".to_string()),
+      StopKind::Dropoff => Some("#00ff00".to_string())
+    }
+  }
   fn get_speed(&self) -> f64 { self.speed }
   fn get_details(&self) -> &Value { &self.details }
-  fn update(&mut self, dt: f64) {
+  fn update(&mut self, dt: f64, _world: &mut World) {
     let mi = self.get_movement_info();
     if let Some(strat) = &mut self.to_robot {
       (self.position, self.direction) = strat.move_entity(mi, dt);
@@ -121,4 +151,32 @@ impl EntityTrait for Drone {
   }
   fn set_position(&mut self, pos: Vector3) { self.position = pos; }
   fn set_direction(&mut self, dir: Vector3) { self.direction = dir; }
+  fn save(&self) -> Value {
+    json!({
+      "id": self.id,
+      "details": self.details,
+      "position": self.position,
+      "direction": self.direction,
+      "destination": self.destination,
+      "speed": self.speed,
+      "availability": self.availability,
+      "capacity": self.capacity,
+      "max_payload": self.max_payload,
+      "stops": self.stops,
+      "to_robot": save_strategy(&self.to_robot),
+      "to_final_destination": save_strategy(&self.to_final_destination)
+    })
+  }
+  fn restore(&mut self, data: &Value) {
+    restore_vector(&mut self.position, &data["position"]);
+    restore_vector(&mut self.direction, &data["direction"]);
+    restore_vector(&mut self.destination, &data["destination"]);
+    self.speed = data["speed"].as_f64().unwrap_or(self.speed);
+    self.availability = data["availability"].as_bool().unwrap_or(self.availability);
+    self.capacity = data["capacity"].as_u64().map_or(self.capacity, |c| c as usize);
+    self.max_payload = data["max_payload"].as_f64().unwrap_or(self.max_payload);
+    self.stops = serde_json::from_value(data["stops"].clone()).unwrap_or_default();
+    self.to_robot = load_strategy(&data["to_robot"]);
+    self.to_final_destination = load_strategy(&data["to_final_destination"]);
+  }
 }
@@ -1,4 +1,5 @@
-use crate::{math::vector3, transit::strategy::MovementInfo};
+use crate::{math::vector3, transit::strategy::{MovementInfo, MovementStrategy}};
+use crate::transit::world::World;
 use serde_json::Value;
 use enum_dispatch::enum_dispatch;
 
@@ -24,7 +25,9 @@ pub trait EntityTrait {
   fn get_speed(&self) -> f64 { 0. }
   fn get_availability(&self) -> bool { false }
   fn get_details(&self) -> &Value;
-  fn update(&mut self, dt: f64);
+  fn update(&mut self, dt: f64, world: &mut World);
+  fn save(&self) -> Value;
+  fn restore(&mut self, data: &Value);
   fn set_position(&mut self, _pos: Vector3) {}
   fn set_direction(&mut self, _dir: Vector3) {}
   fn set_destination(&mut self, _des: Vector3) {}
@@ -47,3 +50,14 @@ pub trait EntityTrait {
     }
   }
 }
+
+pub fn restore_vector(v: &mut Vector3, data: &Value) {
+  if let Ok(p) = serde_json::from_value(data.clone()) { *v = p; }
+}
+
+pub fn save_strategy(strat: &Option<Box<dyn MovementStrategy>>) -> Value {
+  match strat {
+    Some(s) => s.save(),
+    None => Value::Null
+  }
+}
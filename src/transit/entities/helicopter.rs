@@ -1,9 +1,8 @@
-use rand::prelude::*;
-
-use super::entity::EntityTrait;
-use super::super::strategy::{MovementStrategy, PathStrategy};
+use super::entity::{EntityTrait, restore_vector};
+use super::super::strategy::{MovementStrategy, PathStrategy, load_strategy};
 use crate::math::vector3::Vector3;
-use serde_json::Value;
+use crate::transit::world::{SampleArea, World};
+use serde_json::{json, Value};
 
 pub struct Helicopter {
   id: i32,
@@ -12,6 +11,7 @@ pub struct Helicopter {
   direction: Vector3,
   destination: Vector3,
   speed: f64,
+  wander: SampleArea,
   movement: Box<dyn MovementStrategy>
 }
 
@@ -23,10 +23,8 @@ impl Helicopter {
     let mut h = Helicopter {
       id,
       details: data.clone(),
-      speed: match data["speed"].as_f64() {
-        Some(s) => s,
-        _ => 10.
-      },
+      speed: data["speed"].as_f64().unwrap_or(10.),
+      wander: SampleArea::from_json(&data["wander"]).unwrap_or(SampleArea::Bounds),
       position: match data["position"].as_array() {
         Some(a) => match a.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>()[..] {
           [Some(x), Some(y), Some(z)] => Vector3::new(x, y, z),
@@ -47,12 +45,6 @@ impl Helicopter {
     h.destination = h.position;
     h
   }
-  fn get_random_point(&self) -> Vector3 {
-    let x = random::<f64>()*2900. - 1400.;
-    let y = self.position.y;
-    let z = random::<f64>()*1600. - 800.;
-    Vector3::new(x, y, z)
-  }
 }
 
 impl EntityTrait for Helicopter {
@@ -62,12 +54,33 @@ impl EntityTrait for Helicopter {
   fn get_destination(&self) -> Vector3 { self.destination }
   fn get_speed(&self) -> f64 { self.speed }
   fn get_details(&self) -> &Value { &self.details }
-  fn update(&mut self, dt: f64) {
+  fn update(&mut self, dt: f64, world: &mut World) {
     if self.movement.is_completed() {
-      self.movement = Box::new(PathStrategy::from_start_end(self.position, self.get_random_point()));
+      let end = world.sample(&self.wander, None, self.position.y);
+      self.movement = Box::new(PathStrategy::from_start_end(self.position, end));
     }
     (self.position, self.direction) = self.movement.move_entity(self.get_movement_info(), dt);
   }
   fn set_position(&mut self, pos: Vector3) { self.position = pos; }
   fn set_direction(&mut self, dir: Vector3) { self.direction = dir; }
+  fn save(&self) -> Value {
+    json!({
+      "id": self.id,
+      "details": self.details,
+      "position": self.position,
+      "direction": self.direction,
+      "destination": self.destination,
+      "speed": self.speed,
+      "wander": self.wander,
+      "movement": self.movement.save()
+    })
+  }
+  fn restore(&mut self, data: &Value) {
+    restore_vector(&mut self.position, &data["position"]);
+    restore_vector(&mut self.direction, &data["direction"]);
+    restore_vector(&mut self.destination, &data["destination"]);
+    self.speed = data["speed"].as_f64().unwrap_or(self.speed);
+    if let Ok(wander) = serde_json::from_value(data["wander"].clone()) { self.wander = wander; }
+    if let Some(movement) = load_strategy(&data["movement"]) { self.movement = movement; }
+  }
 }
@@ -1,11 +1,10 @@
-use rand::prelude::*;
-
-use super::entity::EntityTrait;
-use super::super::strategy::{MovementStrategy, PathStrategy};
+use super::entity::{EntityTrait, restore_vector, save_strategy};
+use super::super::strategy::{MovementStrategy, PathStrategy, load_strategy};
 use crate::graph::graph::Graph;
 use crate::graph::routing::AStar;
 use crate::math::vector3::Vector3;
-use serde_json::Value;
+use crate::transit::world::{SampleArea, World};
+use serde_json::{json, Value};
 
 pub struct Human {
   id: i32,
@@ -14,6 +13,7 @@ pub struct Human {
   direction: Vector3,
   destination: Vector3,
   speed: f64,
+  wander: SampleArea,
   movement: Option<Box<dyn MovementStrategy>>
 }
 
@@ -25,10 +25,8 @@ impl Human {
     let mut h = Human {
       id,
       details: data.clone(),
-      speed: match data["speed"].as_f64() {
-        Some(s) => s,
-        _ => 10.
-      },
+      speed: data["speed"].as_f64().unwrap_or(10.),
+      wander: SampleArea::from_json(&data["wander"]).unwrap_or(SampleArea::Graph),
       position: match data["position"].as_array() {
         Some(a) => match a.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>()[..] {
           [Some(x), Some(y), Some(z)] => Vector3::new(x, y, z),
@@ -49,18 +47,13 @@ impl Human {
     h.destination = h.position;
     h
   }
-  fn get_random_point(&self) -> Vector3 {
-    let x = random::<f64>()*2900. - 1400.;
-    let y = self.position.y;
-    let z = random::<f64>()*1600. - 800.;
-    Vector3::new(x, y, z)
-  }
-  pub fn set_movement(&mut self, g: &Graph) {
+  pub fn set_movement(&mut self, g: &Graph, world: &mut World) {
     if self.movement.is_some() { return; }
-    let end = self.get_random_point();
-    self.movement = Some(Box::new(PathStrategy::from_path(
-      g.get_path(self.get_position(), end, Box::new(AStar::new())).unwrap()
-    )))
+    let end = world.sample(&self.wander, Some(g), self.position.y);
+    // without a road to walk along the human stays where it is
+    if let Some(path) = g.get_path(self.get_position(), end, Box::new(AStar::new())) {
+      self.movement = Some(Box::new(PathStrategy::from_path(path)));
+    }
   }
 }
 
@@ -71,7 +64,7 @@ impl EntityTrait for Human {
   fn get_destination(&self) -> Vector3 { self.destination }
   fn get_speed(&self) -> f64 { self.speed }
   fn get_details(&self) -> &Value { &self.details }
-  fn update(&mut self, dt: f64) {
+  fn update(&mut self, dt: f64, _world: &mut World) {
     let mi = self.get_movement_info();
     if let Some(strat) = &mut self.movement {
       (self.position, self.direction) = strat.move_entity(mi, dt);
@@ -82,4 +75,24 @@ impl EntityTrait for Human {
   }
   fn set_position(&mut self, pos: Vector3) { self.position = pos; }
   fn set_direction(&mut self, dir: Vector3) { self.direction = dir; }
+  fn save(&self) -> Value {
+    json!({
+      "id": self.id,
+      "details": self.details,
+      "position": self.position,
+      "direction": self.direction,
+      "destination": self.destination,
+      "speed": self.speed,
+      "wander": self.wander,
+      "movement": save_strategy(&self.movement)
+    })
+  }
+  fn restore(&mut self, data: &Value) {
+    restore_vector(&mut self.position, &data["position"]);
+    restore_vector(&mut self.direction, &data["direction"]);
+    restore_vector(&mut self.destination, &data["destination"]);
+    self.speed = data["speed"].as_f64().unwrap_or(self.speed);
+    if let Ok(wander) = serde_json::from_value(data["wander"].clone()) { self.wander = wander; }
+    self.movement = load_strategy(&data["movement"]);
+  }
 }
@@ -1,6 +1,8 @@
-use super::entity::EntityTrait;
+use super::entity::{EntityTrait, restore_vector, save_strategy};
+use super::super::strategy::{MovementStrategy, PathStrategy, load_strategy};
 use crate::math::vector3::Vector3;
-use serde_json::Value;
+use crate::transit::world::World;
+use serde_json::{json, Value};
 
 pub struct Robot {
   id: i32,
@@ -9,8 +11,10 @@ pub struct Robot {
   direction: Vector3,
   destination: Vector3,
   speed: f64,
+  weight: f64,
   availability: bool,
-  strategy_name: String
+  strategy_name: String,
+  movement: Option<Box<dyn MovementStrategy>>
 }
 
 unsafe impl Send for Robot {}
@@ -21,10 +25,8 @@ impl Robot {
     let mut h = Robot {
       id,
       details: data.clone(),
-      speed: match data["speed"].as_f64() {
-        Some(s) => s,
-        _ => 10.
-      },
+      speed: data["speed"].as_f64().unwrap_or(10.),
+      weight: data["weight"].as_f64().unwrap_or(0.),
       position: match data["position"].as_array() {
         Some(a) => match a.iter().map(|v| v.as_f64()).collect::<Vec<Option<f64>>>()[..] {
           [Some(x), Some(y), Some(z)] => Vector3::new(x, y, z),
@@ -41,13 +43,26 @@ impl Robot {
       },
       destination: Vector3::origin(),
       strategy_name: "".to_string(),
-      availability: true
+      availability: true,
+      movement: None
     };
     h.destination = h.position;
     h
   }
   pub fn set_strategy(&mut self, strat: String) { self.strategy_name = strat; }
   pub fn get_strategy(&self) -> String { self.strategy_name.clone() }
+  pub fn get_weight(&self) -> f64 { self.weight }
+  pub fn drive(&mut self, path: Vec<Vector3>) {
+    self.movement = Some(Box::new(PathStrategy::from_path(path)));
+  }
+  pub fn stop(&mut self) { self.movement = None; }
+  pub fn is_driving(&self) -> bool { self.movement.is_some() }
+  pub fn get_drive_eta(&self) -> f64 {
+    match &self.movement {
+      Some(strat) => strat.remaining_time(self.position, self.speed),
+      None => 0.
+    }
+  }
 }
 
 impl EntityTrait for Robot {
@@ -58,9 +73,41 @@ impl EntityTrait for Robot {
   fn get_availability(&self) -> bool { self.availability }
   fn get_speed(&self) -> f64 { self.speed }
   fn get_details(&self) -> &Value { &self.details }
-  fn update(&mut self, _dt: f64) { }
+  fn update(&mut self, dt: f64, _world: &mut World) {
+    let mi = self.get_movement_info();
+    if let Some(strat) = &mut self.movement {
+      (self.position, self.direction) = strat.move_entity(mi, dt);
+      if strat.is_completed() {
+        self.movement = None;
+      }
+    }
+  }
   fn set_position(&mut self, pos: Vector3) { self.position = pos; }
   fn set_direction(&mut self, dir: Vector3) { self.direction = dir; }
+  fn save(&self) -> Value {
+    json!({
+      "id": self.id,
+      "details": self.details,
+      "position": self.position,
+      "direction": self.direction,
+      "destination": self.destination,
+      "speed": self.speed,
+      "availability": self.availability,
+      "weight": self.weight,
+      "strategy_name": self.strategy_name,
+      "movement": save_strategy(&self.movement)
+    })
+  }
+  fn restore(&mut self, data: &Value) {
+    restore_vector(&mut self.position, &data["position"]);
+    restore_vector(&mut self.direction, &data["direction"]);
+    restore_vector(&mut self.destination, &data["destination"]);
+    self.speed = data["speed"].as_f64().unwrap_or(self.speed);
+    self.availability = data["availability"].as_bool().unwrap_or(self.availability);
+    self.weight = data["weight"].as_f64().unwrap_or(self.weight);
+    self.strategy_name = data["strategy_name"].as_str().unwrap_or_default().to_string();
+    self.movement = load_strategy(&data["movement"]);
+  }
   fn set_destination(&mut self, des: Vector3) { self.destination = des }
   fn set_availability(&mut self, avail: bool) { self.availability = avail }
 }
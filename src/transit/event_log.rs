@@ -0,0 +1,94 @@
+use std::fs::{self, File};
+use std::io::Write;
+use serde_json::{json, Value};
+use tracing::error;
+
+// JSON lines log of a run. The first line holds a snapshot of the model when
+// recording started, every following line is either an accepted command or an
+// emitted event, both stamped with the sim time.
+pub struct EventLog {
+  file: File
+}
+
+impl EventLog {
+  pub fn create(path: &str, time: f64, snapshot: Value) -> Result<Self, String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let log = EventLog { file };
+    log.write(&json!({ "time": time, "snapshot": snapshot }));
+    Ok(log)
+  }
+  pub fn command(&self, time: f64, data: &Value) {
+    self.write(&json!({ "time": time, "command": data }));
+  }
+  pub fn event(&self, time: f64, event: &str, details: &Value) {
+    self.write(&json!({ "time": time, "event": event, "details": details }));
+  }
+  // flushes what was written to disk
+  pub fn close(self) -> Result<(), String> {
+    self.file.sync_all().map_err(|e| e.to_string())
+  }
+  fn write(&self, line: &Value) {
+    if let Err(e) = writeln!(&self.file, "{}", line) {
+      error!("could not write event log: {}", e);
+    }
+  }
+}
+
+// A recorded run played back on a timeline of its own. Sim time in the log
+// goes back to zero when the recording reset the simulation, so each command
+// is placed at the sim time that had passed since recording started, which
+// only grows.
+pub struct Replay {
+  start: f64,
+  snapshot: Value,
+  commands: Vec<(f64, Value)>,
+  next: usize,
+  pub clock: f64
+}
+
+impl Replay {
+  pub fn load(path: &str) -> Result<Self, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header: Value = serde_json::from_str(lines.next().ok_or("empty event log")?)
+      .map_err(|e| format!("{}: {}", path, e))?;
+    let start = header["time"].as_f64().ok_or("event log has no start time")?;
+    if header["snapshot"].is_null() { return Err("event log has no snapshot".to_string()); }
+    let mut commands = vec![];
+    let (mut previous, mut offset) = (start, start);
+    for (i, line) in lines.enumerate() {
+      let data: Value = serde_json::from_str(line).map_err(|e| format!("{} line {}: {}", path, i + 2, e))?;
+      if let (Some(time), false) = (data["time"].as_f64(), data["command"].is_null()) {
+        offset += (time - previous).max(0.);
+        previous = time;
+        commands.push((offset, data["command"].clone()));
+      }
+    }
+    Ok(Replay { start, snapshot: header["snapshot"].clone(), commands, next: 0, clock: start })
+  }
+  pub fn get_snapshot(&self) -> &Value { &self.snapshot }
+  pub fn get_end(&self) -> f64 {
+    self.commands.last().map(|(t, _)| *t).unwrap_or(self.start)
+  }
+  pub fn rewind(&mut self) {
+    self.next = 0;
+    self.clock = self.start;
+  }
+  // the next recorded command that was received at or before `time` on the
+  // replay's timeline
+  pub fn next_command(&mut self, time: f64) -> Option<Value> {
+    let (t, command) = self.commands.get(self.next)?;
+    if *t > time { return None; }
+    self.next += 1;
+    Some(command.clone())
+  }
+  pub fn to_json(&self) -> Value {
+    json!({
+      "start": self.start,
+      "end": self.get_end(),
+      "time": self.clock,
+      "position": self.next,
+      "commands": self.commands.len()
+    })
+  }
+}
@@ -14,7 +14,7 @@ pub trait EntityFactory {
 pub struct DroneFactory {}
 impl EntityFactory for DroneFactory {
   fn create_entity(&self, id: i32, data: &Value) -> Option<Entity> {
-    match data["type"].as_str().unwrap() {
+    match data["type"].as_str()? {
       "drone" => Some(Entity::Drone(Drone::new(id, data))),
       _ => None
     }
@@ -24,7 +24,7 @@ impl EntityFactory for DroneFactory {
 pub struct RobotFactory {}
 impl EntityFactory for RobotFactory {
   fn create_entity(&self, id: i32, data: &Value) -> Option<Entity> {
-    match data["type"].as_str().unwrap() {
+    match data["type"].as_str()? {
       "robot" => Some(Entity::Robot(Robot::new(id, data))),
       _ => None
     }
@@ -34,7 +34,7 @@ impl EntityFactory for RobotFactory {
 pub struct HumanFactory {}
 impl EntityFactory for HumanFactory {
   fn create_entity(&self, id: i32, data: &Value) -> Option<Entity> {
-    match data["type"].as_str().unwrap() {
+    match data["type"].as_str()? {
       "human" => Some(Entity::Human(Human::new(id, data))),
       _ => None
     }
@@ -44,7 +44,7 @@ impl EntityFactory for HumanFactory {
 pub struct HelicopterFactory {}
 impl EntityFactory for HelicopterFactory {
   fn create_entity(&self, id: i32, data: &Value) -> Option<Entity> {
-    match data["type"].as_str().unwrap() {
+    match data["type"].as_str()? {
       "helicopter" => Some(Entity::Helicopter(Helicopter::new(id, data))),
       _ => None
     }
@@ -62,11 +62,16 @@ impl CompositeFactory {
   pub fn add_factory(&mut self, factory: Box<dyn EntityFactory + Send + Sync>) {
     self.factories.push(factory);
   }
+  pub fn get_id(&self) -> i32 { self.id }
+  pub fn set_id(&mut self, id: i32) { self.id = id; }
   pub fn create_any_entity(&mut self, data: &Value) -> Option<Entity> {
     self.id += 1;
     self.create_entity(self.id, data)
   }
 }
+impl Default for CompositeFactory {
+  fn default() -> Self { CompositeFactory::new() }
+}
 impl EntityFactory for CompositeFactory {
   fn create_entity(&self, id: i32, data: &Value) -> Option<Entity> {
     for factory in self.factories.iter() {
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::math::vector3::Vector3;
+
+use super::entities::entity::{Entity, EntityTrait};
+use super::trip::{Trip, TripMode, TripState};
+
+// fleet wide numbers are sampled once per this many sim seconds
+const SAMPLE_INTERVAL: f64 = 1.;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TripRecord {
+  pub trip_id: i32,
+  pub name: String,
+  pub passenger_id: i32,
+  pub carrier_id: Option<i32>,
+  pub strategy: String,
+  pub mode: TripMode,
+  pub state: TripState,
+  pub requested: f64,
+  pub assigned: Option<f64>,
+  pub picked_up: Option<f64>,
+  pub finished: Option<f64>,
+  pub wait_time: Option<f64>,
+  pub pickup_time: Option<f64>,
+  pub in_transit_time: Option<f64>,
+  pub distance: f64
+}
+
+impl TripRecord {
+  // Times come from the trip history: waiting lasts until a carrier is
+  // assigned, pickup until the passenger is on board (or starts driving) and
+  // transit until the trip ends.
+  fn new(trip: &Trip, distance: f64) -> Self {
+    let first = |f: fn(&TripState) -> bool| trip.history.iter().find(|(s, _)| f(s)).map(|(_, t)| *t);
+    let requested = trip.history.first().map(|(_, t)| *t).unwrap_or_default();
+    let assigned = first(|s| *s == TripState::Assigned);
+    let picked_up = first(|s| s.is_picked_up());
+    let finished = first(|s| s.is_terminal());
+    let span = |from: Option<f64>, to: Option<f64>| Some(to? - from?);
+    TripRecord {
+      trip_id: trip.id,
+      name: trip.name.clone(),
+      passenger_id: trip.passenger_id,
+      carrier_id: trip.carrier_id,
+      strategy: trip.strategy.clone(),
+      mode: trip.mode,
+      state: trip.state,
+      requested, assigned, picked_up, finished,
+      wait_time: span(Some(requested), assigned.or(finished)),
+      pickup_time: span(assigned, picked_up),
+      in_transit_time: span(picked_up, finished),
+      distance
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DroneRecord {
+  pub id: i32,
+  pub name: String,
+  pub busy_time: f64,
+  pub idle_time: f64,
+  pub distance: f64
+}
+
+impl DroneRecord {
+  pub fn get_utilisation(&self) -> f64 {
+    let total = self.busy_time + self.idle_time;
+    if total > 0. { self.busy_time / total } else { 0. }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetSample {
+  pub time: f64,
+  pub drones: usize,
+  pub busy: usize,
+  pub idle: usize,
+  pub queue: usize,
+  pub active_trips: usize
+}
+
+#[derive(Default)]
+pub struct Metrics {
+  trips: Vec<TripRecord>,
+  distances: HashMap<i32, f64>,
+  positions: HashMap<i32, Vector3>,
+  drones: BTreeMap<i32, DroneRecord>,
+  samples: Vec<FleetSample>,
+  next_sample: f64
+}
+
+impl Metrics {
+  pub fn new() -> Self { Metrics::default() }
+  pub fn update(&mut self, time: f64, dt: f64, entities: &BTreeMap<i32, Entity>, trips: &[Trip]) {
+    let mut moved = HashMap::new();
+    for (id, entity) in entities.iter() {
+      if !matches!(entity, Entity::Drone(_)) { continue; }
+      let pos = entity.get_position();
+      let step = self.positions.insert(*id, pos).map(|p| p.distance(&pos)).unwrap_or(0.);
+      moved.insert(*id, step);
+      let drone = self.drones.entry(*id).or_insert_with(|| DroneRecord {
+        id: *id,
+        name: entity.get_details()["name"].as_str().unwrap_or_default().to_string(),
+        ..Default::default()
+      });
+      drone.distance += step;
+      if entity.get_availability() { drone.idle_time += dt; } else { drone.busy_time += dt; }
+    }
+    for trip in trips.iter().filter(|t| t.state.is_picked_up() && !t.driving) {
+      if let Some(step) = trip.carrier_id.and_then(|id| moved.get(&id)) {
+        *self.distances.entry(trip.id).or_default() += step;
+      }
+    }
+    if time >= self.next_sample {
+      let drones = moved.len();
+      let busy = entities.values()
+        .filter(|e| matches!(e, Entity::Drone(_)) && !e.get_availability())
+        .count();
+      self.samples.push(FleetSample {
+        time, drones, busy,
+        idle: drones - busy,
+        queue: trips.iter().filter(|t| t.state == TripState::Requested).count(),
+        active_trips: trips.iter().filter(|t| !t.state.is_terminal()).count()
+      });
+      self.next_sample = time + SAMPLE_INTERVAL;
+    }
+  }
+  pub fn finish_trip(&mut self, trip: &Trip) {
+    let distance = self.distances.remove(&trip.id).unwrap_or(0.);
+    self.trips.push(TripRecord::new(trip, distance));
+  }
+  pub fn get_completed(&self) -> Vec<(TripState, usize)> {
+    [TripState::Delivered, TripState::Cancelled, TripState::Failed].into_iter()
+      .map(|state| (state, self.trips.iter().filter(|r| r.state == state).count()))
+      .collect()
+  }
+  // finished trips followed by the ones still running
+  fn trip_records(&self, active: &[Trip]) -> Vec<TripRecord> {
+    let mut records = self.trips.clone();
+    records.extend(active.iter().map(|t| TripRecord::new(t, self.distances.get(&t.id).copied().unwrap_or(0.))));
+    records
+  }
+  fn strategies(records: &[TripRecord]) -> Vec<Value> {
+    let mut groups: BTreeMap<&str, Vec<&TripRecord>> = BTreeMap::new();
+    for r in records {
+      groups.entry(r.strategy.as_str()).or_default().push(r);
+    }
+    let mean = |values: Vec<f64>| if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) };
+    groups.into_iter().map(|(strategy, trips)| json!({
+      "strategy": strategy,
+      "trips": trips.len(),
+      "delivered": trips.iter().filter(|r| r.state == TripState::Delivered).count(),
+      "mean_wait_time": mean(trips.iter().filter_map(|r| r.wait_time).collect()),
+      "mean_pickup_time": mean(trips.iter().filter_map(|r| r.pickup_time).collect()),
+      "mean_in_transit_time": mean(trips.iter().filter_map(|r| r.in_transit_time).collect()),
+      "mean_distance": mean(trips.iter().filter(|r| r.finished.is_some()).map(|r| r.distance).collect())
+    })).collect()
+  }
+  pub fn to_json(&self, time: f64, active: &[Trip]) -> Value {
+    let records = self.trip_records(active);
+    json!({
+      "time": time,
+      "trips": records,
+      "drones": self.drones.values()
+        .map(|d| {
+          let mut v = json!(d);
+          v["utilisation"] = json!(d.get_utilisation());
+          v
+        })
+        .collect::<Vec<Value>>(),
+      "fleet": self.samples,
+      "strategies": Metrics::strategies(&records)
+    })
+  }
+  // one table per kind of record, each as the text of a csv file
+  pub fn to_csv(&self, time: f64, active: &[Trip]) -> Vec<(&'static str, String)> {
+    let data = self.to_json(time, active);
+    ["trips", "drones", "fleet", "strategies"].into_iter()
+      .map(|table| (table, csv_table(data[table].as_array().unwrap())))
+      .collect()
+  }
+  // Writes json, or with a .csv path one file per table next to it. Returns
+  // the files written.
+  pub fn write(&self, time: f64, active: &[Trip], path: &str) -> Result<Vec<String>, String> {
+    let path = Path::new(path);
+    if path.extension().is_some_and(|e| e == "csv") {
+      let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("metrics");
+      self.to_csv(time, active).into_iter().map(|(table, text)| {
+        let file = path.with_file_name(format!("{}_{}.csv", stem, table));
+        fs::write(&file, text).map_err(|e| format!("{}: {}", file.display(), e))?;
+        Ok(file.display().to_string())
+      }).collect()
+    } else {
+      fs::write(path, self.to_json(time, active).to_string()).map_err(|e| format!("{}: {}", path.display(), e))?;
+      Ok(vec![path.display().to_string()])
+    }
+  }
+}
+
+// Prometheus text exposition, one metric family at a time
+#[derive(Default)]
+pub struct Exposition {
+  text: String
+}
+
+impl Exposition {
+  pub fn new() -> Self { Exposition::default() }
+  // each sample is the part of the series name after `name`, e.g. a label
+  // set like `{type="drone"}` or a suffix like `_sum`, and its value
+  pub fn add(&mut self, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    self.text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+    for (series, value) in samples {
+      self.text += &format!("{}{} {}\n", name, series, value);
+    }
+  }
+  pub fn single(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+    self.add(name, kind, help, &[(String::new(), value)]);
+  }
+  pub fn finish(self) -> String { self.text }
+}
+
+fn csv_table(rows: &[Value]) -> String {
+  let columns = match rows.first().and_then(|r| r.as_object()) {
+    Some(row) => row.keys().cloned().collect::<Vec<String>>(),
+    None => return String::new()
+  };
+  let mut text = columns.join(",") + "\n";
+  for row in rows {
+    let fields = columns.iter().map(|c| match &row[c] {
+      Value::Null => String::new(),
+      Value::String(s) if s.contains([',', '"', '\n']) => format!("\"{}\"", s.replace('"', "\"\"")),
+      Value::String(s) => s.clone(),
+      v => v.to_string()
+    }).collect::<Vec<String>>();
+    text += &(fields.join(",") + "\n");
+  }
+  text
+}
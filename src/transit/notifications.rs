@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Value};
+
+use crate::math::vector3::Vector3;
+use super::entities::entity::{Entity, EntityTrait};
+use super::trip::TripState;
+
+// Readable messages about what entities and trips are doing, for the viewer's
+// notification bar. Each is a Notification event with the entity it is about,
+// the message as `info` and a `value` naming the kind, as the viewer expects.
+#[derive(Default)]
+pub struct Notifier {
+  colors: BTreeMap<i32, Option<String>>
+}
+
+impl Notifier {
+  pub fn new() -> Self { Notifier::default() }
+  pub fn reset(&mut self) { self.colors.clear(); }
+  // entities whose color changed since the last call, as updateDetails
+  pub fn colors(&mut self, entities: &BTreeMap<i32, Entity>, time: f64) -> Vec<(String, Value)> {
+    self.colors.retain(|id, _| entities.contains_key(id));
+    let mut notes = vec![];
+    for (id, entity) in entities.iter() {
+      let color = entity.get_color();
+      if self.colors.get(id).unwrap_or(&None) == &color { continue; }
+      self.colors.insert(*id, color.clone());
+      let info = match &color {
+        Some(color) => format!("{} color changed to {}", name(entities, *id), color),
+        None => format!("{} color cleared", name(entities, *id))
+      };
+      let mut note = notification(Some(*id), "updateDetails", &info, time);
+      note.1["details"] = json!({ "color": color });
+      notes.push(note);
+    }
+    notes
+  }
+}
+
+// what a TripStateChanged event means for the people watching, None for the
+// steps in between that are not worth a message
+pub fn trip(details: &Value, entities: &BTreeMap<i32, Entity>) -> Option<(String, Value)> {
+  let state: TripState = serde_json::from_value(details["state"].clone()).ok()?;
+  let trip_id = details["trip_id"].as_i64()?;
+  let passenger_id = details["passenger_id"].as_i64()? as i32;
+  let carrier_id = details["carrier_id"].as_i64().map(|id| id as i32);
+  let passenger = match (entities.contains_key(&passenger_id), details["name"].as_str()) {
+    (false, Some(name)) => name.to_string(),
+    _ => name(entities, passenger_id)
+  };
+  let carrier = carrier_id.map(|id| name(entities, id)).unwrap_or_default();
+  let (value, info) = match state {
+    TripState::Requested => ("scheduled", format!("{} requested trip {}", passenger, trip_id)),
+    TripState::Assigned if carrier_id.is_some() => ("assigned", format!("{} assigned to pick up {}", carrier, passenger)),
+    TripState::PickedUp => ("en route", format!("{} picked up {}", carrier, passenger)),
+    TripState::Delivered if carrier_id.is_some() => ("delivered", format!("{} delivered {}", carrier, passenger)),
+    TripState::Delivered => ("delivered", format!("{} arrived", passenger)),
+    TripState::Cancelled => ("cancelled", format!("Trip {} for {} cancelled", trip_id, passenger)),
+    TripState::Failed => ("failed", format!("Trip {} for {} failed: {}", trip_id, passenger, details["reason"].as_str().unwrap_or("unknown reason"))),
+    _ => return None
+  };
+  let time = details["time"].as_f64().unwrap_or_default();
+  let mut note = notification(Some(carrier_id.unwrap_or(passenger_id)), value, &info, time);
+  note.1["trip_id"] = json!(trip_id);
+  Some(note)
+}
+
+// a drone starting a new leg along `path`, or stopping when it is empty, the
+// path itself goes out once as the observe event
+pub fn leg(entities: &BTreeMap<i32, Entity>, id: i32, path: &[Vector3], time: f64) -> (String, Value) {
+  if path.is_empty() {
+    return notification(Some(id), "idle", &format!("{} stopped moving", name(entities, id)), time);
+  }
+  notification(Some(id), "moving", &format!("{} now moving", name(entities, id)), time)
+}
+
+fn notification(id: Option<i32>, value: &str, info: &str, time: f64) -> (String, Value) {
+  ("Notification".to_string(), json!({
+    "id": id,
+    "value": value,
+    "info": info,
+    "time": time
+  }))
+}
+
+fn name(entities: &BTreeMap<i32, Entity>, id: i32) -> String {
+  entities.get(&id)
+    .and_then(|e| e.get_details()["name"].as_str().map(|n| n.to_string()))
+    .unwrap_or(format!("Entity #{}", id))
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use serde_json::{json, Value};
+use warp::ws::Message;
+
+use crate::encoding::Encoding;
+use crate::math::vector3::Vector3;
+use crate::subscription::Subscription;
+
+// events sent in the client's chosen encoding, the rest always go out as json
+const BINARY_EVENTS: [&str; 1] = ["WorldUpdate"];
+
+// An entity in a WorldUpdate as area filters see it: its type, where it is,
+// where clients last saw it and all of its state for clients it comes into
+// view for, who missed what changed while it was out of it.
+#[derive(Clone)]
+pub struct Sighting {
+  pub kind: String,
+  pub pos: Vector3,
+  pub seen: Option<Vector3>,
+  pub state: Value
+}
+
+// An event as published by the simulation to every client task. Clients that
+// do not filter share its frames, which are encoded once per encoding.
+pub struct Outgoing {
+  event: String,
+  message: Value,
+  // the entities of a WorldUpdate, for type and area filters
+  entities: HashMap<i32, Sighting>,
+  frames: [OnceLock<Message>; 3]
+}
+
+impl Outgoing {
+  pub fn new(event: &str, details: &Value, entities: HashMap<i32, Sighting>) -> Self {
+    Outgoing {
+      event: event.to_string(),
+      message: json!({
+        "event": event,
+        "details": details
+      }),
+      entities,
+      frames: Default::default()
+    }
+  }
+  pub fn wants(&self, subscription: &Subscription) -> bool {
+    subscription.wants(&self.event)
+  }
+  // Per tick updates may be dropped for slow clients when a newer one of the
+  // same kind is queued, other events never are.
+  pub fn is_droppable(&self) -> bool {
+    matches!(self.event.as_str(), "WorldUpdate" | "TripEtas")
+  }
+  // the last event a client gets before its connection is closed
+  pub fn is_final(&self) -> bool {
+    self.event == "ServerShutdown"
+  }
+  pub fn is_same_kind(&self, other: &Outgoing) -> bool {
+    self.event == other.event
+  }
+  // What replaces `newer` when `older` is dropped. Poses are deltas, so a
+  // WorldUpdate gets the changes of `older` followed by its own and its
+  // entities were last seen where `older` found them, anything else already
+  // says all there is.
+  pub fn merge(older: &Outgoing, newer: &Outgoing) -> Option<Outgoing> {
+    if newer.event != "WorldUpdate" { return None; }
+    let mut entities = older.message["details"]["entities"].as_array().cloned().unwrap_or_default();
+    for change in newer.message["details"]["entities"].as_array().into_iter().flatten() {
+      match entities.iter_mut().find(|e| e["id"] == change["id"]) {
+        Some(entity) => for (key, value) in change.as_object().into_iter().flatten() {
+          entity[key] = value.clone();
+        },
+        None => entities.push(change.clone())
+      }
+    }
+    let mut sightings = older.entities.clone();
+    for (id, sighting) in newer.entities.iter() {
+      let seen = older.entities.get(id).map_or(sighting.seen, |s| s.seen);
+      sightings.insert(*id, Sighting { seen, ..sighting.clone() });
+    }
+    Some(Outgoing::new(&newer.event, &json!({
+      "time": newer.message["details"]["time"],
+      "entities": entities
+    }), sightings))
+  }
+  // the frame a client gets for this event, None when it is not wanted
+  pub fn frame(&self, encoding: Encoding, subscription: &Subscription) -> Option<Message> {
+    if !subscription.wants(&self.event) { return None; }
+    if !subscription.is_filtered() {
+      return Some(self.frames[encoding as usize].get_or_init(|| self.encode(encoding, &self.message)).clone());
+    }
+    let details = self.filter(subscription)?;
+    Some(self.encode(encoding, &json!({ "event": self.event, "details": details })))
+  }
+  fn encode(&self, encoding: Encoding, message: &Value) -> Message {
+    if BINARY_EVENTS.contains(&self.event.as_str()) {
+      encoding.encode(message)
+    } else {
+      Message::text(message.to_string())
+    }
+  }
+  // What a filtering client gets of the event, None when nothing is left.
+  // Poses are filtered by type and area, entities are announced by type
+  // alone so that they are known when they move into the area, and get all
+  // of their state when they do.
+  fn filter(&self, subscription: &Subscription) -> Option<Value> {
+    let details = &self.message["details"];
+    match self.event.as_str() {
+      "WorldUpdate" => {
+        let entities = details["entities"].as_array()?.iter()
+          .filter_map(|e| {
+            let sighting = self.entities.get(&(e["id"].as_i64()? as i32))?;
+            if !subscription.wants_entity(&sighting.kind, sighting.pos) { return None; }
+            let entered = sighting.seen.is_some_and(|seen| !subscription.wants_entity(&sighting.kind, seen));
+            Some(if entered { sighting.state.clone() } else { e.clone() })
+          })
+          .collect::<Vec<Value>>();
+        if entities.is_empty() { return None; }
+        Some(json!({ "time": details["time"], "entities": entities }))
+      },
+      "AddEntity" | "UpdateEntity" => subscription.wants_type(details["details"]["type"].as_str().unwrap_or_default())
+        .then(|| details.clone()),
+      _ => Some(details.clone())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sighting(kind: &str, x: f64) -> Sighting {
+    Sighting { kind: kind.to_string(), pos: Vector3::new(x, 0., 0.), seen: Some(Vector3::new(x, 0., 0.)), state: Value::Null }
+  }
+
+  fn details(outgoing: &Outgoing, subscription: &Value) -> Option<Value> {
+    let frame = outgoing.frame(Encoding::Json, &Subscription::from_json(subscription).unwrap())?;
+    Some(serde_json::from_str::<Value>(frame.to_str().unwrap()).unwrap()["details"].clone())
+  }
+
+  #[test]
+  fn filters_entity_events_by_type_and_area() {
+    let update = Outgoing::new("WorldUpdate", &json!({ "time": 1., "entities": [{ "id": 1 }, { "id": 2 }, { "id": 3 }] }),
+      HashMap::from([(1, sighting("drone", 10.)), (2, sighting("drone", 500.)), (3, sighting("robot", 10.))]));
+    let area = json!({ "min": [0, -1, -1], "max": [100, 1, 1] });
+    assert_eq!(details(&update, &json!({ "area": area })).unwrap()["entities"], json!([{ "id": 1 }, { "id": 3 }]));
+    assert_eq!(details(&update, &json!({ "types": ["drone"] })).unwrap()["entities"], json!([{ "id": 1 }, { "id": 2 }]));
+    assert_eq!(details(&update, &json!({ "types": ["drone"], "area": area })).unwrap()["entities"], json!([{ "id": 1 }]));
+    assert_eq!(details(&update, &json!({ "types": ["human"] })), None);
+    assert_eq!(details(&update, &json!({ "topics": ["trips"] })), None);
+    let added = Outgoing::new("AddEntity", &json!({ "id": 2, "details": { "type": "drone" } }), HashMap::new());
+    assert!(details(&added, &json!({ "types": ["drone"], "area": area })).is_some());
+    assert_eq!(details(&added, &json!({ "types": ["robot"] })), None);
+  }
+}
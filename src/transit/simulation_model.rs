@@ -1,48 +1,98 @@
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
 
 use crate::{transit::entities::{
     entity::{Entity, EntityTrait},
-  }, math::vector3::Vector3, graph::graph::Graph};
-use serde_json::Value;
+  }, math::vector3::Vector3, graph::graph::{Graph, path_length}, graph::routing::{AStar, DepthFirstSearch, Dijkstras, SearchStrategy}};
+use serde_json::{json, Value};
+use tracing::{debug, debug_span, info, warn};
 
-use super::factory::{CompositeFactory, DroneFactory, RobotFactory, HumanFactory, HelicopterFactory};
-
-#[derive(Debug)]
-pub struct Trip {
-  carrier_id: i32,
-  passenger_id: i32,
-  active: bool,
-  finished: bool,
-  current_destination: Vector3
-}
+use super::dispatch::{plan_mode, plan_route, DispatchPolicy, Request, Stop, StopKind};
+use super::metrics::Metrics;
+use super::notifications::{self, Notifier};
+use super::factory::{CompositeFactory, EntityFactory, DroneFactory, RobotFactory, HumanFactory, HelicopterFactory};
+use super::strategy::search_strategy;
+use super::trip::{Trip, TripMode, TripState};
+use super::world::{World, WorldBounds};
 
 pub struct SimulationModel {
-  pub entities: HashMap<i32, Entity>,
-  scheduler: HashSet<i32>,
+  pub entities: BTreeMap<i32, Entity>,
+  scheduler: BTreeSet<i32>,
   trips: Vec<Trip>,
+  trip_id: i32,
+  time: f64,
+  events: Vec<(String, Value)>,
+  world: World,
+  metrics: Metrics,
+  legs: BTreeMap<i32, Vec<Vector3>>,
+  notifier: Notifier,
+  policy: DispatchPolicy,
+  speeds: BTreeMap<String, f64>,
   factory: CompositeFactory,
-  graph: Graph
+  graph: Arc<Graph>
 }
 
-fn get_nearest_entity(entities: &HashMap<i32, Vector3>, e: Vector3) -> Option<(i32, Vector3)> {
-  let mut x = entities.iter()
-    .map(|(i, o)| (o.distance(&e), *i, *o))
-    .collect::<Vec<(f64, i32, Vector3)>>();
-  x.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-  match x.get(0) {
-    Some((_, entity, pos)) => Some((*entity, *pos)),
+fn get_request(trip: &Trip, passenger: Option<&Entity>) -> Option<Request> {
+  match passenger {
+    Some(Entity::Robot(r)) => Some(Request {
+      trip_id: trip.id,
+      pickup: trip.pickup.unwrap_or(r.get_position()),
+      dropoff: r.get_destination(),
+      weight: r.get_weight(),
+      strategy: r.get_strategy()
+    }),
     _ => None
   }
 }
 
+fn estimate_stops(graph: &Graph, start: Vector3, stops: &mut [Stop], speed: f64) {
+  let mut prev = start;
+  for s in stops.iter_mut() {
+    s.estimate = match s.kind {
+      StopKind::Pickup => if speed > 0. { prev.distance(&s.position) / speed } else { 0. },
+      StopKind::Dropoff => search_strategy(&s.strategy, prev, s.position, graph).remaining_time(prev, speed)
+    };
+    prev = s.position;
+  }
+}
+
+// the closest road node to `pos`, or `pos` itself without a road network
+fn nearest_ground(graph: &Graph, pos: Vector3) -> Vector3 {
+  if graph.nodes.is_empty() { return pos; }
+  graph.nodes[graph.nearest_node(pos) as usize].get_position()
+}
+
+fn state_changed(trip: &mut Trip, state: TripState, time: f64) -> Option<(String, Value)> {
+  let Some(details) = trip.transition(state, time) else {
+    warn!(trip_id = trip.id, time, "trip cannot go from {:?} to {:?}", trip.state, state);
+    return None;
+  };
+  info!(trip_id = trip.id, passenger_id = trip.passenger_id, carrier_id = trip.carrier_id, time, "trip {:?}", state);
+  Some(("TripStateChanged".to_string(), details))
+}
+
+impl Default for SimulationModel {
+  fn default() -> Self { SimulationModel::new() }
+}
+
 impl SimulationModel {
   pub fn new() -> Self {
+    let seed = rand::random::<u64>();
     let mut model = SimulationModel {
-      entities: HashMap::new(),
-      scheduler: HashSet::new(),
+      entities: BTreeMap::new(),
+      scheduler: BTreeSet::new(),
       trips: vec![],
+      trip_id: 0,
+      time: 0.,
+      events: vec![],
+      world: World::new(seed),
+      metrics: Metrics::new(),
+      legs: BTreeMap::new(),
+      notifier: Notifier::new(),
+      policy: DispatchPolicy::default(),
+      speeds: BTreeMap::new(),
       factory: CompositeFactory::new(), 
-      graph: Graph::new()
+      graph: Arc::default()
     };
     model.factory.add_factory(Box::new(DroneFactory {}));
     model.factory.add_factory(Box::new(RobotFactory {}));
@@ -50,114 +100,560 @@ impl SimulationModel {
     model.factory.add_factory(Box::new(HelicopterFactory {}));
     model
   }
-  pub fn set_graph(&mut self, graph: Graph) {
+  pub fn get_seed(&self) -> u64 { self.world.get_seed() }
+  pub fn set_seed(&mut self, seed: u64) { self.world.set_seed(seed); }
+  pub fn set_policy(&mut self, policy: DispatchPolicy) { self.policy = policy; }
+  // speeds by entity type for entities created without one
+  pub fn set_default_speeds(&mut self, speeds: BTreeMap<String, f64>) { self.speeds = speeds; }
+  pub fn set_graph(&mut self, graph: Arc<Graph>) {
+    if let Some(bounds) = WorldBounds::from_graph(&graph) {
+      self.world.set_bounds(bounds);
+    }
     self.graph = graph;
   }
-  pub fn create_entity(&mut self, data: Value) -> Option<Entity> {
-    let entity_name = data["name"].to_string();
-    let p: Vec<f64> = data["position"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect();
-    let entity_pos = Vector3::new(p[0], p[1], p[2]);
-    println!("{}: {}", entity_name, entity_pos);
-    self.factory.create_any_entity(&data)
+  pub fn set_world_bounds(&mut self, data: &Value) -> Option<Value> {
+    if let Some(bounds) = WorldBounds::from_json(data) {
+      self.world.set_bounds(bounds);
+    }
+    if let Some(regions) = data["regions"].as_object() {
+      for (name, region) in regions {
+        self.world.set_region(name.clone(), WorldBounds::from_json(region)?);
+      }
+    }
+    Some(self.world.to_json())
+  }
+  pub fn create_entity(&mut self, mut data: Value) -> Option<Entity> {
+    if let (Value::Null, Some(speed)) = (&data["speed"], data["type"].as_str().and_then(|t| self.speeds.get(t))) {
+      data["speed"] = json!(speed);
+    }
+    let p = data["position"].as_array()?.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>()?;
+    if p.len() != 3 { return None; }
+    let entity = self.factory.create_any_entity(&data)?;
+    debug!(entity_id = entity.get_id(), name = data["name"].as_str(), kind = data["type"].as_str(), position = %Vector3::new(p[0], p[1], p[2]), time = self.time, "entity created");
+    Some(entity)
+  }
+  pub fn get_time(&self) -> f64 { self.time }
+  pub fn save_snapshot(&self) -> Value {
+    json!({
+      "time": self.time,
+      "trip_id": self.trip_id,
+      "entity_id": self.factory.get_id(),
+      "world": self.world.save(),
+      "entities": self.entities.values().map(|e| e.save()).collect::<Vec<Value>>(),
+      "trips": self.trips,
+      "scheduler": self.scheduler
+    })
+  }
+  // Everything is rebuilt before it replaces the current state, so a broken
+  // snapshot leaves the model untouched.
+  pub fn load_snapshot(&mut self, data: &Value) -> Option<()> {
+    let mut entities = BTreeMap::new();
+    for e in data["entities"].as_array()? {
+      let id = e["id"].as_i64()? as i32;
+      let mut entity = self.factory.create_entity(id, &e["details"])?;
+      entity.restore(e);
+      entities.insert(id, entity);
+    }
+    let trips: Vec<Trip> = serde_json::from_value(data["trips"].clone()).ok()?;
+    let scheduler: BTreeSet<i32> = serde_json::from_value(data["scheduler"].clone()).ok()?;
+    let mut world = World::new(0);
+    world.restore(&data["world"])?;
+    self.time = data["time"].as_f64()?;
+    self.trip_id = data["trip_id"].as_i64()? as i32;
+    self.factory.set_id(data["entity_id"].as_i64()? as i32);
+    self.world = world;
+    self.entities = entities;
+    self.trips = trips;
+    self.scheduler = scheduler;
+    self.metrics = Metrics::new();
+    self.legs.clear();
+    self.notifier.reset();
+    self.events.clear();
+    Some(())
+  }
+  // Drops every entity and trip and starts the clock over. The world keeps
+  // its bounds, and its random numbers start over from the seed.
+  pub fn reset(&mut self) {
+    self.entities.clear();
+    self.scheduler.clear();
+    self.trips.clear();
+    self.trip_id = 0;
+    self.time = 0.;
+    self.factory.set_id(0);
+    self.world.set_seed(self.world.get_seed());
+    self.metrics = Metrics::new();
+    self.legs.clear();
+    self.notifier.reset();
+    self.events.clear();
+  }
+  pub fn get_entity_counts(&self) -> BTreeMap<&'static str, usize> {
+    let mut counts = BTreeMap::from([("drone", 0), ("robot", 0), ("human", 0), ("helicopter", 0)]);
+    for entity in self.entities.values() {
+      let kind = match entity {
+        Entity::Drone(_) => "drone",
+        Entity::Robot(_) => "robot",
+        Entity::Human(_) => "human",
+        Entity::Helicopter(_) => "helicopter"
+      };
+      *counts.entry(kind).or_default() += 1;
+    }
+    counts
+  }
+  pub fn get_active_trips(&self) -> usize {
+    self.trips.iter().filter(|t| !t.state.is_terminal()).count()
+  }
+  pub fn get_queued_trips(&self) -> usize {
+    self.trips.iter().filter(|t| t.state == TripState::Requested).count()
+  }
+  pub fn get_completed_trips(&self) -> Vec<(TripState, usize)> {
+    self.metrics.get_completed()
+  }
+  pub fn get_metrics(&self) -> Value {
+    self.metrics.to_json(self.time, &self.trips)
+  }
+  pub fn get_metrics_csv(&self) -> Vec<(&'static str, String)> {
+    self.metrics.to_csv(self.time, &self.trips)
+  }
+  pub fn write_metrics(&self, path: &str) -> Result<Vec<String>, String> {
+    self.metrics.write(self.time, &self.trips, path)
+  }
+  // the events since the last call, each trip step followed by its notification
+  pub fn take_events(&mut self) -> Vec<(String, Value)> {
+    let mut events = vec![];
+    for (event, details) in std::mem::take(&mut self.events) {
+      let note = if event == "TripStateChanged" { notifications::trip(&details, &self.entities) } else { None };
+      events.push((event, details));
+      events.extend(note);
+    }
+    events
   }
   pub fn schedule_trip(&mut self, data: &Value) -> Option<Value> {
-    let name = data["name"].to_string();
-    let start = data["start"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect::<Vec<f64>>();
-    let end = data["end"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect::<Vec<f64>>();
-    for (_, entity) in self.entities.iter_mut() {
-      if let Entity::Robot(robot) = entity {
-        if robot.get_availability() && robot.get_details()["name"].to_string() == name {
-          robot.set_destination(Vector3::from_vec(&end));
-          robot.set_strategy(data["search"].as_str().unwrap().to_string());
-          self.scheduler.insert(robot.get_id());
-          println!("{}: {:?} --> {:?}", name, start, end);
+    let start = data["start"].as_array()?.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>()?;
+    let end = data["end"].as_array()?.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>()?;
+    if end.len() != 3 { return None; }
+    let strategy = data["search"].as_str()?.to_string();
+    let mode = match data["mode"].as_str() {
+      Some("drive") => Some(TripMode::Drive),
+      Some("mixed") => Some(TripMode::Mixed),
+      Some("auto") => None,
+      _ => Some(TripMode::Fly)
+    };
+    let mut robots = self.entities.iter()
+      .filter(|(_, e)| matches!(e, Entity::Robot(_)) && e.get_availability() && e.get_details()["name"] == data["name"])
+      .map(|(id, _)| *id)
+      .collect::<Vec<i32>>();
+    robots.sort();
+    let mut trip_ids = vec![];
+    for id in robots {
+      let (pos, speed) = (self.entities[&id].get_position(), self.entities[&id].get_speed());
+      let dest = Vector3::from_vec(&end);
+      self.trip_id += 1;
+      let mut trip = Trip::new(self.trip_id, data["name"].as_str().unwrap_or_default().to_string(), id, pos, self.time);
+      trip.strategy = strategy.clone();
+      let ground = self.graph.get_path(pos, dest, Box::new(AStar::new())).filter(|g| !g.is_empty());
+      let plan = match (mode, ground) {
+        (Some(TripMode::Fly), _) | (_, None) => None,
+        (mode, Some(ground)) => {
+          let carrier = self.nearest_carrier(pos);
+          let plan = plan_mode(mode, &ground, pos, speed, carrier, |a, b| match carrier {
+            Some((_, s)) => search_strategy(&strategy, a, b, &self.graph).remaining_time(a, s),
+            None => f64::INFINITY
+          });
+          Some((plan, ground))
         }
+      };
+      let mut flight_dest = dest;
+      if let Some((plan, ground)) = plan {
+        trip.mode = plan.mode;
+        match plan.mode {
+          TripMode::Drive => {
+            trip.driving = true;
+            if let Some(Entity::Robot(r)) = self.entities.get_mut(&id) {
+              r.drive(ground);
+            }
+          },
+          TripMode::Mixed => {
+            trip.pickup = Some(ground[plan.rendezvous]);
+            trip.last_leg = ground[plan.handoff..].to_vec();
+            flight_dest = ground[plan.handoff];
+            if let Some(Entity::Robot(r)) = self.entities.get_mut(&id) {
+              r.drive(ground[..=plan.rendezvous].to_vec());
+            }
+          },
+          TripMode::Fly => ()
+        }
+      }
+      self.events.push(("TripStateChanged".to_string(), trip.to_json()));
+      if let Some(Entity::Robot(robot)) = self.entities.get_mut(&id) {
+        robot.set_destination(flight_dest);
+        robot.set_strategy(strategy.clone());
+        robot.set_availability(false);
+      }
+      if trip.driving {
+        self.events.extend(state_changed(&mut trip, TripState::Assigned, self.time));
+        self.events.extend(state_changed(&mut trip, TripState::InTransit, self.time));
+      } else {
+        self.scheduler.insert(id);
       }
+      info!(trip_id = trip.id, passenger_id = id, mode = ?trip.mode, time = self.time, "trip requested from {:?} to {:?}", start, end);
+      self.trips.push(trip);
+      trip_ids.push(self.trip_id);
     }
-    Some(data.clone())
+    let mut response = data.clone();
+    response["trip_ids"] = json!(trip_ids);
+    Some(response)
+  }
+  // Removing a passenger fails its trips and releases their carriers, removing
+  // a carrier fails its trips and sets their passengers down on the ground.
+  pub fn remove_entity(&mut self, id: i32) -> Option<Entity> {
+    let entity = self.entities.remove(&id)?;
+    self.scheduler.remove(&id);
+    for trip in self.trips.iter_mut().filter(|t| !t.state.is_terminal()) {
+      let reason = if trip.passenger_id == id {
+        if let Some(Entity::Drone(d)) = trip.carrier_id.and_then(|c| self.entities.get_mut(&c)) {
+          d.remove_stops(trip.id, &self.graph);
+        }
+        "passenger removed"
+      } else if trip.carrier_id == Some(id) && !trip.driving {
+        if let Some(passenger) = self.entities.get_mut(&trip.passenger_id) {
+          if let Entity::Robot(r) = passenger {
+            r.stop();
+          }
+          if trip.state.is_picked_up() {
+            passenger.set_position(nearest_ground(&self.graph, entity.get_position()));
+          }
+          passenger.set_availability(true);
+        }
+        self.scheduler.remove(&trip.passenger_id);
+        "carrier removed"
+      } else { continue; };
+      if let Some((event, mut details)) = state_changed(trip, TripState::Failed, self.time) {
+        details["reason"] = json!(reason);
+        self.events.push((event, details));
+      }
+    }
+    self.retire_trips();
+    debug!(entity_id = id, time = self.time, "entity removed");
+    Some(entity)
+  }
+  pub fn get_trips(&self) -> Vec<Value> {
+    self.trips.iter().map(|t| {
+      let mut trip = t.to_json();
+      trip["eta"] = self.get_trip_eta(t.id).unwrap_or(Value::Null);
+      trip
+    }).collect()
+  }
+  // A route over the road network with one of the graph searches, or a
+  // straight line for any other name
+  pub fn find_path(&self, from: Vector3, to: Vector3, search: &str) -> Option<Vec<Vector3>> {
+    let strategy: Box<dyn SearchStrategy> = match search {
+      "astar" => Box::new(AStar::new()),
+      "dfs" => Box::new(DepthFirstSearch::new()),
+      "dijkstra" => Box::new(Dijkstras::new()),
+      _ => return Some(vec![from, to])
+    };
+    self.graph.get_path(from, to, strategy)
+  }
+  fn nearest_carrier(&self, pos: Vector3) -> Option<(Vector3, f64)> {
+    let mut drones = self.entities.values()
+      .filter(|e| matches!(e, Entity::Drone(_)))
+      .map(|e| (!e.get_availability(), e.get_position().distance(&pos), e.get_position(), e.get_speed()))
+      .collect::<Vec<(bool, f64, Vector3, f64)>>();
+    drones.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    drones.first().map(|d| (d.2, d.3))
+  }
+  // the live trip with the given `trip_id`, finished trips are left alone
+  fn find_trip(&self, data: &Value) -> Option<usize> {
+    let id = data["trip_id"].as_i64()? as i32;
+    self.trips.iter().position(|t| t.id == id && !t.state.is_terminal())
+  }
+  pub fn cancel_trip(&mut self, data: &Value) -> Option<Value> {
+    let index = self.find_trip(data)?;
+    let mut trip = self.trips.remove(index);
+    let picked_up = trip.state.is_picked_up() && !trip.driving;
+    self.scheduler.remove(&trip.passenger_id);
+    let carrier_pos = match trip.carrier_id.and_then(|id| self.entities.get_mut(&id)) {
+      Some(Entity::Drone(d)) => {
+        d.remove_stops(trip.id, &self.graph);
+        Some(d.get_position())
+      },
+      _ => None
+    };
+    if let Some(passenger) = self.entities.get_mut(&trip.passenger_id) {
+      if let Entity::Robot(r) = passenger {
+        r.stop();
+      }
+      if let (true, Some(pos)) = (picked_up, carrier_pos) {
+        let landing = if data["land"].as_str() == Some("node") { nearest_ground(&self.graph, pos) } else { pos };
+        passenger.set_position(landing);
+      }
+      passenger.set_availability(true);
+    }
+    self.events.extend(state_changed(&mut trip, TripState::Cancelled, self.time));
+    self.metrics.finish_trip(&trip);
+    Some(json!({
+      "trip_id": trip.id,
+      "carrier_id": trip.carrier_id,
+      "passenger_id": trip.passenger_id,
+      "picked_up": picked_up
+    }))
+  }
+  pub fn reassign_trip(&mut self, data: &Value) -> Option<Value> {
+    let trip_id = data["trip_id"].as_i64()? as i32;
+    let drone_id = data["drone_id"].as_i64()? as i32;
+    let trip = self.trips.iter_mut().find(|t| t.id == trip_id)?;
+    // once the robot is on board the trip stays with its drone, cancel it to
+    // set the robot down first
+    if trip.state.is_terminal() || trip.state.is_picked_up() { return None; }
+    let request = get_request(trip, self.entities.get(&trip.passenger_id))?;
+    let mut stops = match self.entities.get(&drone_id) {
+      Some(Entity::Drone(d)) if d.get_availability() => {
+        plan_route(d.get_position(), &[request], d.get_capacity(), d.get_max_payload())
+      },
+      _ => return None
+    };
+    if stops.is_empty() { return None; }
+    let old_carrier = trip.carrier_id;
+    if let Some(Entity::Drone(d)) = old_carrier.and_then(|id| self.entities.get_mut(&id)) {
+      d.remove_stops(trip_id, &self.graph);
+    }
+    self.scheduler.remove(&trip.passenger_id);
+    if let Some(Entity::Drone(d)) = self.entities.get_mut(&drone_id) {
+      estimate_stops(&self.graph, d.get_position(), &mut stops, d.get_speed());
+      d.assign_route(stops, &self.graph);
+    }
+    trip.carrier_id = Some(drone_id);
+    self.events.extend(state_changed(trip, TripState::Assigned, self.time));
+    self.events.extend(state_changed(trip, TripState::EnRouteToPickup, self.time));
+    info!(trip_id, previous_carrier_id = old_carrier, carrier_id = drone_id, time = self.time, "trip reassigned");
+    Some(json!({
+      "trip_id": trip_id,
+      "previous_carrier_id": old_carrier,
+      "carrier_id": drone_id,
+      "passenger_id": trip.passenger_id
+    }))
   }
   pub fn update(&mut self, dt: f64) {
+    let _span = debug_span!("update", time = self.time, dt).entered();
+    self.time += dt;
     self.create_trips();
     self.update_human_movements();
     self.update_all_entities(dt);
+    self.metrics.update(self.time, dt, &self.entities, &self.trips);
     self.update_trips();
+    self.trace_legs();
+    let colors = self.notifier.colors(&self.entities, self.time);
+    self.events.extend(colors);
+  }
+  // Publishes the path a drone searched for whenever it starts a new leg, and
+  // when it stops, as the "observe" events the viewer draws.
+  fn trace_legs(&mut self) {
+    self.legs.retain(|id, _| self.entities.contains_key(id));
+    for (id, entity) in self.entities.iter() {
+      let path = match entity {
+        Entity::Drone(d) => d.get_leg_path(),
+        _ => continue
+      };
+      if self.legs.insert(*id, path.clone()).unwrap_or_default() == path { continue; }
+      self.events.push(("observe".to_string(), if path.is_empty() {
+        json!({ "id": id, "value": "idle" })
+      } else {
+        json!({
+          "id": id,
+          "value": "moving",
+          "path": path.iter().map(|p| [p.x, p.y, p.z]).collect::<Vec<[f64; 3]>>()
+        })
+      }));
+      self.events.push(notifications::leg(&self.entities, *id, &path, self.time));
+    }
+  }
+  pub fn get_trip_eta(&self, trip_id: i32) -> Option<Value> {
+    let trip = self.trips.iter().find(|t| t.id == trip_id)?;
+    let robot = match self.entities.get(&trip.passenger_id) {
+      Some(Entity::Robot(r)) => r,
+      _ => return None
+    };
+    let (pickup, dropoff) = if trip.driving {
+      (0., robot.get_drive_eta())
+    } else {
+      let drone = match self.entities.get(&trip.carrier_id?) {
+        Some(Entity::Drone(d)) => d,
+        _ => return None
+      };
+      let last_leg = if robot.get_speed() > 0. { path_length(&trip.last_leg) / robot.get_speed() } else { 0. };
+      (drone.get_stop_eta(trip.id, StopKind::Pickup).unwrap_or(0.),
+        drone.get_stop_eta(trip.id, StopKind::Dropoff)? + last_leg)
+    };
+    Some(json!({
+      "trip_id": trip.id,
+      "name": trip.name,
+      "passenger_id": trip.passenger_id,
+      "carrier_id": trip.carrier_id,
+      "pickup_eta": pickup,
+      "dropoff_eta": dropoff
+    }))
+  }
+  pub fn get_trip_etas(&self) -> Vec<Value> {
+    self.trips.iter().filter_map(|t| self.get_trip_eta(t.id)).collect()
   }
   fn create_trips(&mut self) {
-    let mut distances = self.scheduler.iter()
-      .map(|k| (k, &self.entities[k]))
-      .map(|(k, v)| (*k, v.get_position()))
-      .collect::<HashMap<i32, Vector3>>();
-    let mut scheduled = HashSet::new();
-    for (_, entity) in self.entities.iter_mut() {
-      match entity {
-        Entity::Drone(d) => {
-          if d.get_availability() && self.scheduler.len() > 0 {
-            match get_nearest_entity(&distances, d.get_position()) {
-              Some((e, p)) => {
-                let t = Trip {
-                  carrier_id: d.get_id(),
-                  passenger_id: e,
-                  active: false,
-                  finished: false,
-                  current_destination: p
-                };
-                self.trips.push(t);
-                d.establish_trip(p);
-                scheduled.insert(e);
-                distances.remove(&e);
-                self.scheduler.remove(&e);
-              },
-              _ => ()
-            };
-          }
-        },
-        _ => ()
+    let requests = self.trips.iter()
+      .filter(|t| t.state == TripState::Requested && self.scheduler.contains(&t.passenger_id))
+      .filter_map(|t| get_request(t, self.entities.get(&t.passenger_id)))
+      .collect::<Vec<Request>>();
+    if requests.is_empty() { return; }
+    let routes = match self.policy {
+      DispatchPolicy::Pooled => self.plan_pooled(requests),
+      DispatchPolicy::Nearest => self.plan_nearest(requests)
+    };
+    for (id, stops) in routes {
+      for stop in stops.iter().filter(|s| s.kind == StopKind::Pickup) {
+        if let Some(trip) = self.trips.iter_mut().find(|t| t.id == stop.trip_id) {
+          self.scheduler.remove(&trip.passenger_id);
+          trip.carrier_id = Some(id);
+          trip.current_destination = stop.position;
+          self.events.extend(state_changed(trip, TripState::Assigned, self.time));
+          self.events.extend(state_changed(trip, TripState::EnRouteToPickup, self.time));
+        }
+      }
+      if let Some(Entity::Drone(d)) = self.entities.get_mut(&id) {
+        d.assign_route(stops, &self.graph);
       }
     }
-    for id in scheduled {
-      self.entities.get_mut(&id).unwrap().set_availability(false);
+  }
+  // each idle drone in turn takes the requests closest to it
+  fn plan_pooled(&self, mut requests: Vec<Request>) -> Vec<(i32, Vec<Stop>)> {
+    let mut routes = vec![];
+    for (id, entity) in self.entities.iter() {
+      if requests.is_empty() { break; }
+      let d = match entity {
+        Entity::Drone(d) if d.get_availability() => d,
+        _ => continue
+      };
+      let pos = d.get_position();
+      requests.sort_by(|a, b| a.pickup.distance(&pos).total_cmp(&b.pickup.distance(&pos)));
+      let mut stops = plan_route(pos, &requests, d.get_capacity(), d.get_max_payload());
+      if stops.is_empty() { continue; }
+      estimate_stops(&self.graph, pos, &mut stops, d.get_speed());
+      requests.retain(|r| !stops.iter().any(|s| s.trip_id == r.trip_id));
+      routes.push((*id, stops));
+    }
+    routes
+  }
+  // the oldest request gets the closest idle drone able to carry it
+  fn plan_nearest(&self, requests: Vec<Request>) -> Vec<(i32, Vec<Stop>)> {
+    let mut routes: Vec<(i32, Vec<Stop>)> = vec![];
+    for request in requests {
+      let nearest = self.entities.iter()
+        .filter_map(|(id, e)| match e {
+          Entity::Drone(d) if d.get_availability() && !routes.iter().any(|(r, _)| r == id) => Some(d),
+          _ => None
+        })
+        .filter(|d| d.get_max_payload() >= request.weight)
+        .min_by(|a, b| a.get_position().distance(&request.pickup).total_cmp(&b.get_position().distance(&request.pickup)));
+      if let Some(d) = nearest {
+        let pos = d.get_position();
+        let mut stops = plan_route(pos, std::slice::from_ref(&request), 1, d.get_max_payload());
+        if stops.is_empty() { continue; }
+        estimate_stops(&self.graph, pos, &mut stops, d.get_speed());
+        routes.push((d.get_id(), stops));
+      }
     }
+    routes
   }
   fn update_human_movements(&mut self) {
     for (_, entity) in self.entities.iter_mut() {
-      match entity {
-        Entity::Human(h) => h.set_movement(&self.graph),
-        _ => ()
+      if let Entity::Human(h) = entity {
+        h.set_movement(&self.graph, &mut self.world);
       }
     }
   }
   fn update_all_entities(&mut self, dt: f64) {
     for (_, entity) in self.entities.iter_mut() {
-      entity.update(dt);
+      entity.update(dt, &mut self.world);
     }
   }
   fn update_trips(&mut self) {
-    for trip in self.trips.iter_mut() {
-      let (pos1, dir1) = {
-        let x= self.entities.get(&trip.carrier_id).unwrap();
-        (x.get_position(), x.get_direction())
+    let mut carriers = self.trips.iter()
+      .filter_map(|t| t.carrier_id)
+      .collect::<Vec<i32>>();
+    carriers.sort();
+    carriers.dedup();
+    let driving = self.trips.iter()
+      .filter(|t| matches!(self.entities.get(&t.passenger_id), Some(Entity::Robot(r)) if r.is_driving()))
+      .map(|t| t.id)
+      .collect::<HashSet<i32>>();
+    for carrier_id in carriers {
+      let d = match self.entities.get_mut(&carrier_id) {
+        Some(Entity::Drone(d)) if d.leg_completed() => d,
+        _ => continue
       };
-      let trip_info = match self.entities.get(&trip.passenger_id) {
-        Some(Entity::Robot(r)) => Some((r.get_strategy(), r.get_destination())),
-        _ => None
+      // wait at a rendezvous until the robot has driven there
+      if let Some(s) = d.stops.front() {
+        if s.kind == StopKind::Pickup && driving.contains(&s.trip_id) { continue; }
+      }
+      let reached = match d.stops.pop_front() {
+        Some(s) => s,
+        None => continue
       };
-      if let Entity::Drone(d) = self.entities.get_mut(&trip.carrier_id).unwrap() {
-        if (trip.active && d.to_final_destination.is_none()) || trip_info.is_none() {
-          d.finish_trip();
-          trip.finished = true;
-        } else if !trip.active && d.to_robot.is_none() {
-          let (strat, dest) = trip_info.unwrap();
-          d.continue_trip(strat, dest, &self.graph);
-          trip.current_destination = dest;
-          trip.active = true;
+      d.start_leg(&self.graph);
+      if d.stops.is_empty() {
+        d.finish_trip();
+      }
+      if let Some(trip) = self.trips.iter_mut().find(|t| t.id == reached.trip_id) {
+        match reached.kind {
+          StopKind::Pickup => {
+            self.events.extend(state_changed(trip, TripState::PickedUp, self.time));
+            self.events.extend(state_changed(trip, TripState::InTransit, self.time));
+          },
+          StopKind::Dropoff if !trip.last_leg.is_empty() => {
+            if let Some(Entity::Robot(r)) = self.entities.get_mut(&trip.passenger_id) {
+              r.drive(std::mem::take(&mut trip.last_leg));
+            }
+            trip.driving = true;
+          },
+          StopKind::Dropoff => {
+            trip.current_destination = reached.position;
+            self.events.extend(state_changed(trip, TripState::Delivered, self.time));
+          }
         }
       }
-      if trip.active {
-        let e2 = self.entities.get_mut(&trip.passenger_id).unwrap();
+    }
+    for trip in self.trips.iter_mut() {
+      if !trip.driving || trip.state != TripState::InTransit { continue; }
+      if let Some(Entity::Robot(r)) = self.entities.get(&trip.passenger_id) {
+        if r.is_driving() { continue; }
+        trip.current_destination = r.get_position();
+        self.events.extend(state_changed(trip, TripState::Delivered, self.time));
+      }
+    }
+    for trip in self.trips.iter_mut() {
+      if trip.state.is_terminal() || matches!(self.entities.get(&trip.passenger_id), Some(Entity::Robot(_))) {
+        continue;
+      }
+      if let Some(Entity::Drone(d)) = trip.carrier_id.and_then(|id| self.entities.get_mut(&id)) {
+        d.remove_stops(trip.id, &self.graph);
+      }
+      self.scheduler.remove(&trip.passenger_id);
+      self.events.extend(state_changed(trip, TripState::Failed, self.time));
+    }
+    for trip in self.trips.iter() {
+      if trip.driving || !matches!(trip.state, TripState::InTransit | TripState::Delivered) { continue; }
+      let (pos1, dir1) = match trip.carrier_id.and_then(|id| self.entities.get(&id)) {
+        Some(x) => (x.get_position(), x.get_direction()),
+        None => continue
+      };
+      if let Some(e2) = self.entities.get_mut(&trip.passenger_id) {
         e2.set_position(pos1);
         e2.set_direction(dir1);
       }
     }
-    self.trips.retain(|t| !t.finished);
+    self.retire_trips();
+  }
+  fn retire_trips(&mut self) {
+    for trip in self.trips.iter().filter(|t| t.state.is_terminal()) {
+      self.metrics.finish_trip(trip);
+    }
+    self.trips.retain(|t| !t.state.is_terminal());
   }
 }
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::Value;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info_span, Instrument};
+
+use crate::client::ClientHandle;
+use super::metrics::Exposition;
+use super::outgoing::Outgoing;
+use super::transit_service::TransitServer;
+
+type Job = Box<dyn FnOnce(&mut TransitServer) + Send>;
+
+// Runs one job on the server. A job that panics is logged and dropped so the
+// session carries on for its other clients, a `call` waiting on it gets None.
+fn run(server: &mut TransitServer, job: impl FnOnce(&mut TransitServer)) {
+  if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| job(server))) {
+    server.recover();
+    let message = panic.downcast_ref::<&str>().map(|m| m.to_string())
+      .or_else(|| panic.downcast_ref::<String>().cloned())
+      .unwrap_or_default();
+    error!(message, "job panicked and was dropped");
+  }
+}
+
+// A TransitServer running in a task of its own. Websocket readers and the
+// http api queue work for it, and what it emits is published to the client
+// tasks, so neither ticks nor clients wait on each other. The task ends once
+// every handle is dropped.
+#[derive(Clone)]
+pub struct SimulationHandle {
+  jobs: mpsc::UnboundedSender<Job>,
+  output: broadcast::Sender<Arc<Outgoing>>,
+  clients: Arc<Mutex<BTreeMap<String, ClientHandle>>>
+}
+
+impl SimulationHandle {
+  // With a tick rate the simulation advances on its own clock, otherwise
+  // only when a client sends an Update.
+  pub fn spawn(session: &str, mut server: TransitServer, tick_rate: Option<f64>) -> Self {
+    let output = server.get_output();
+    let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+    tokio::task::spawn(async move {
+      let mut ticks = tick_rate.map(|hz| {
+        let mut ticks = time::interval(Duration::from_secs_f64(1. / hz));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        ticks
+      });
+      loop {
+        tokio::select! {
+          job = queue.recv() => match job {
+            Some(job) => run(&mut server, job),
+            None => break
+          },
+          _ = async { ticks.as_mut().unwrap().tick().await }, if ticks.is_some() => run(&mut server, |s| s.tick())
+        }
+        // let the client tasks take what was published before the next job
+        tokio::task::yield_now().await;
+      }
+    }.instrument(info_span!("session", %session)));
+    SimulationHandle { jobs, output, clients: Arc::default() }
+  }
+  // a message from a client, as if it had been received directly
+  pub fn send(&self, data: Value) {
+    let _ = self.jobs.send(Box::new(move |server| server.recieve(data)));
+  }
+  // Runs `f` on the simulation and waits for its result, None when the task
+  // has stopped.
+  pub async fn call<R: Send + 'static>(&self, f: impl FnOnce(&mut TransitServer) -> R + Send + 'static) -> Option<R> {
+    let (result, reply) = oneshot::channel();
+    self.jobs.send(Box::new(move |server| {
+      let _ = result.send(f(server));
+    })).ok()?;
+    reply.await.ok()
+  }
+  pub fn subscribe(&self) -> broadcast::Receiver<Arc<Outgoing>> {
+    self.output.subscribe()
+  }
+  pub fn get_client_count(&self) -> usize {
+    self.output.receiver_count()
+  }
+  pub fn add_client(&self, client_id: &str, client: ClientHandle) {
+    self.clients.lock().unwrap().insert(client_id.to_string(), client);
+  }
+  pub fn get_client(&self, client_id: &str) -> Option<ClientHandle> {
+    self.clients.lock().unwrap().get(client_id).cloned()
+  }
+  pub fn remove_client(&self, client_id: &str) {
+    self.clients.lock().unwrap().remove(client_id);
+  }
+  // how far behind each connected client is
+  pub fn get_client_metrics(&self, out: &mut Exposition) {
+    let clients = self.clients.lock().unwrap();
+    let series = |value: fn(&ClientHandle) -> f64| clients.iter()
+      .map(|(id, client)| (format!("{{client=\"{}\"}}", id), value(client)))
+      .collect::<Vec<(String, f64)>>();
+    out.add("transit_client_queue_length", "gauge", "Frames waiting to be sent to each client.", &series(|c| c.outbox.get_length() as f64));
+    out.add("transit_client_dropped_updates_total", "counter", "Updates dropped because a client fell behind.", &series(|c| c.outbox.get_dropped() as f64));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Config;
+
+  #[tokio::test]
+  async fn survives_a_panicking_job() {
+    let handle = SimulationHandle::spawn("test", TransitServer::new(&Config::default()), None);
+    assert_eq!(handle.call(|_| -> i32 { panic!("bad command") }).await, None);
+    assert_eq!(handle.call(|s| s.get_time()).await, Some(0.));
+  }
+}
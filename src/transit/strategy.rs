@@ -1,4 +1,7 @@
+use crate::graph::graph::Graph;
+use crate::graph::routing::{AStar, DepthFirstSearch, Dijkstras};
 use crate::math::vector3::Vector3;
+use serde_json::{json, Value};
 
 #[derive(Debug)]
 pub struct MovementInfo {
@@ -11,6 +14,40 @@ pub struct MovementInfo {
 pub trait MovementStrategy {
   fn move_entity(&mut self, entity: MovementInfo, dt: f64) -> (Vector3, Vector3);
   fn is_completed(&self) -> bool;
+  fn remaining_time(&self, position: Vector3, speed: f64) -> f64;
+  fn save(&self) -> Value;
+  fn get_path(&self) -> &[Vector3];
+}
+
+impl MovementStrategy for Box<dyn MovementStrategy> {
+  fn move_entity(&mut self, entity: MovementInfo, dt: f64) -> (Vector3, Vector3) { (**self).move_entity(entity, dt) }
+  fn is_completed(&self) -> bool { (**self).is_completed() }
+  fn remaining_time(&self, position: Vector3, speed: f64) -> f64 { (**self).remaining_time(position, speed) }
+  fn save(&self) -> Value { (**self).save() }
+  fn get_path(&self) -> &[Vector3] { (**self).get_path() }
+}
+
+// Rebuilds a strategy saved with MovementStrategy::save, decorators are
+// restored around their inner strategy.
+pub fn load_strategy(data: &Value) -> Option<Box<dyn MovementStrategy>> {
+  match data["type"].as_str()? {
+    "path" => Some(Box::new(PathStrategy {
+      path: serde_json::from_value(data["path"].clone()).ok()?,
+      index: data["index"].as_u64()? as usize
+    })),
+    "spin" => Some(Box::new(SpinDecorator {
+      strat: load_strategy(&data["strategy"])?,
+      time: data["time"].as_f64()?
+    })),
+    "jump" => Some(Box::new(JumpDecorator {
+      strat: load_strategy(&data["strategy"])?,
+      time: data["time"].as_f64()?,
+      height: data["height"].as_f64()?,
+      going_up: data["going_up"].as_bool()?,
+      y_level: data["y_level"].as_f64()?
+    })),
+    _ => None
+  }
 }
 
 pub struct PathStrategy {
@@ -35,6 +72,9 @@ impl PathStrategy {
     }
   }
 }
+impl Default for PathStrategy {
+  fn default() -> Self { PathStrategy::new() }
+}
 
 impl MovementStrategy for PathStrategy {
   fn move_entity(&mut self, entity: MovementInfo, dt: f64) -> (Vector3, Vector3) {
@@ -50,6 +90,18 @@ impl MovementStrategy for PathStrategy {
   fn is_completed(&self) -> bool {
     self.index >= self.path.len()
   }
+  fn remaining_time(&self, position: Vector3, speed: f64) -> f64 {
+    if self.is_completed() || speed <= 0. { return 0.; }
+    let mut distance = position.distance(&self.path[self.index]);
+    for i in self.index + 1..self.path.len() {
+      distance += self.path[i - 1].distance(&self.path[i]);
+    }
+    distance / speed
+  }
+  fn save(&self) -> Value {
+    json!({ "type": "path", "path": self.path, "index": self.index })
+  }
+  fn get_path(&self) -> &[Vector3] { &self.path }
 }
 
 pub struct SpinDecorator<T: MovementStrategy> {
@@ -77,6 +129,13 @@ impl <T: MovementStrategy> MovementStrategy for SpinDecorator<T> {
   fn is_completed(&self) -> bool {
     self.time <= 0.
   }
+  fn remaining_time(&self, position: Vector3, speed: f64) -> f64 {
+    self.strat.remaining_time(position, speed) + self.time.max(0.)
+  }
+  fn save(&self) -> Value {
+    json!({ "type": "spin", "time": self.time, "strategy": self.strat.save() })
+  }
+  fn get_path(&self) -> &[Vector3] { self.strat.get_path() }
 }
 
 pub struct JumpDecorator<T: MovementStrategy> {
@@ -118,4 +177,57 @@ impl <T: MovementStrategy> MovementStrategy for JumpDecorator<T> {
   fn is_completed(&self) -> bool {
     self.time <= 0.
   }
+  fn remaining_time(&self, position: Vector3, speed: f64) -> f64 {
+    self.strat.remaining_time(position, speed) + self.time.max(0.)
+  }
+  fn save(&self) -> Value {
+    json!({
+      "type": "jump",
+      "time": self.time,
+      "height": self.height,
+      "going_up": self.going_up,
+      "y_level": self.y_level,
+      "strategy": self.strat.save()
+    })
+  }
+  fn get_path(&self) -> &[Vector3] { self.strat.get_path() }
+}
+
+// Moves along the road network with the named search, or straight to `end`
+// when the network has no path between the two.
+pub fn search_strategy(name: &str, start: Vector3, end: Vector3, graph: &Graph) -> Box<dyn MovementStrategy> {
+  match name {
+    "astar" => Box::new(
+      JumpDecorator::new(
+        PathStrategy::from_path(
+          graph.get_path(start, end, 
+            Box::new(AStar::new())
+          ).unwrap_or(vec![start, end])
+        )
+      , 4., 10.)
+    ),
+    "dfs" => Box::new(
+      SpinDecorator::new(
+        JumpDecorator::new(
+          PathStrategy::from_path(
+            graph.get_path(start, end, 
+              Box::new(DepthFirstSearch::new())
+            ).unwrap_or(vec![start, end])
+          )
+        , 4., 10.)
+      , 4.)
+    ),
+    "dijkstra" => Box::new(
+      JumpDecorator::new(
+        SpinDecorator::new(
+          PathStrategy::from_path(
+            graph.get_path(start, end, 
+              Box::new(Dijkstras::new())
+            ).unwrap_or(vec![start, end])
+          )
+        , 4.)
+      , 4., 10.)
+    ),
+    _ => Box::new(PathStrategy::from_start_end(start, end))
+  }
 }
@@ -1,50 +1,249 @@
-use std::collections::HashMap;
-use std::time::SystemTime;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tracing::{error, info_span, warn};
 
-use crate::{Client, graph::parsers::obj_graph_parser};
+use crate::{config::Config, graph::graph::path_length, math::vector3::Vector3};
 use super::simulation_model;
 use super::entities::entity;
+use super::delta::{full_change, DeltaEncoder};
+use super::event_log::{EventLog, Replay};
+use super::metrics::Exposition;
+use super::outgoing::{Outgoing, Sighting};
 
 use simulation_model::SimulationModel;
 use entity::{Entity, EntityTrait};
 
+// events a client may fall behind by before it starts missing some
+const OUTPUT_CAPACITY: usize = 1024;
+// what the simulation answers to, as told to clients when they connect
+pub const COMMANDS: [&str; 15] = [
+  "CreateEntity", "RemoveEntity", "ScheduleTrip", "CancelTrip", "ReassignTrip", "Update",
+  "SetSeed", "SetWorldBounds", "SaveSnapshot", "LoadSnapshot", "ExportMetrics",
+  "ReplaySeek", "ReplayInfo", "runScript", "kill"
+];
+// commands answered to the client asking for them rather than published
+pub const REQUESTS: [&str; 3] = ["SaveSnapshot", "LoadSnapshot", "ExportMetrics"];
+// scene entries that only concern the viewer
+const VIEWER_COMMANDS: [&str; 2] = ["SetScene", "AddMesh"];
+// ticks per second are averaged over this many wall clock seconds
+const TICK_WINDOW: f64 = 10.;
+// the most sim time one Update may cover, so that a single command cannot
+// hold up the session, the rest is lost as when the server falls behind
+const MAX_STEP: f64 = 1.;
+
 pub struct TransitServer {
-  pub clients: HashMap<String, Client>,
+  output: broadcast::Sender<Arc<Outgoing>>,
   total_time: f64,
   start: SystemTime,
   model: SimulationModel,
+  log: Option<EventLog>,
+  replay: Option<Replay>,
+  muted: bool,
+  // set once the server is shutting down, nothing runs after that
+  stopped: bool,
+  // set by a kill, updates are ignored until the world is reset
+  halted: bool,
+  // what is left of a script and the sim time it carries on at
+  script: VecDeque<Value>,
+  script_resume: f64,
+  delta: DeltaEncoder,
+  ticks: u64,
+  update_time: f64,
+  last_update_time: f64,
+  recent_ticks: VecDeque<Instant>,
+  ticking: bool,
+  sim_speed: f64,
+  broadcast_interval: Option<Duration>,
+  last_broadcast: Option<Instant>,
+  data_dir: PathBuf
 }
 
 impl TransitServer {
-  pub fn new() -> Self {
+  pub fn new(config: &Config) -> Self {
     let mut server = TransitServer {
-      clients: HashMap::new(),
+      output: broadcast::channel(OUTPUT_CAPACITY).0,
       total_time: 0.,
       start: SystemTime::now(),
-      model: SimulationModel::new()
+      model: SimulationModel::new(),
+      log: None,
+      replay: None,
+      muted: false,
+      stopped: false,
+      halted: false,
+      script: VecDeque::new(),
+      script_resume: 0.,
+      delta: DeltaEncoder::new(),
+      ticks: 0,
+      update_time: 0.,
+      last_update_time: 0.,
+      recent_ticks: VecDeque::new(),
+      ticking: config.tick_rate.is_some(),
+      sim_speed: 1.,
+      broadcast_interval: config.broadcast_rate.map(|hz| Duration::from_secs_f64(1. / hz)),
+      last_broadcast: None,
+      data_dir: PathBuf::from(&config.data_dir)
     };
-    server.model.set_graph(obj_graph_parser("web/assets/model/routes.obj".to_string()));
+    server.model.set_graph(config.network.clone());
+    server.model.set_policy(config.get_dispatch());
+    server.model.set_default_speeds(config.speeds.clone());
+    if let Some(seed) = config.seed {
+      server.model.set_seed(seed);
+    }
     server
   }
-  pub fn recieve_message(&mut self, message: &str) {
-    let data: Value = serde_json::from_str(message).unwrap();
+  pub fn recieve(&mut self, data: Value) {
+    // a server keeping its own time only takes the speed from client updates
+    if self.ticking && data["command"] == "Update" {
+      if let Some(speed) = data["simSpeed"].as_f64().filter(|s| s.is_finite() && *s >= 0.) {
+        self.sim_speed = speed;
+      }
+      return;
+    }
+    self.dispatch(data);
+  }
+  // advances the simulation by the wall clock time since the last update
+  pub fn tick(&mut self) {
+    self.dispatch(json!({ "command": "Update", "simSpeed": self.sim_speed }));
+  }
+  fn dispatch(&mut self, data: Value) {
+    if self.stopped { return; }
+    if self.replay.is_some() {
+      self.replay_message(&data);
+      self.flush_events();
+    } else {
+      self.execute(data);
+    }
+  }
+  // Runs a command as if a client had sent it and returns its result, which
+  // is how the http api reaches the model. Nothing runs while replaying.
+  pub fn execute(&mut self, mut data: Value) -> Option<Value> {
+    if self.replay.is_some() || self.stopped { return None; }
+    if data["command"] == "Update" {
+      let delta = self.elapsed();
+      if data["dt"].is_null() {
+        data["dt"] = json!(delta * data["simSpeed"].as_f64().unwrap_or(1.));
+      }
+    }
+    self.log_command(&data);
+    let result = self.run_command(&data);
+    self.flush_events();
+    result
+  }
+  // Runs one of the `REQUESTS` for a client, the event and details to send
+  // back to it if there are any, or why it could not be done. A loaded
+  // snapshot is news for every client and is published instead.
+  pub fn request(&mut self, data: Value) -> Result<Option<(&'static str, Value)>, String> {
+    if self.replay.is_some() { return Err("not while replaying".to_string()); }
+    if self.stopped { return Err("the simulation has stopped".to_string()); }
+    self.log_command(&data);
+    let result = self.answer(&data);
+    self.flush_events();
+    result
+  }
+  fn log_command(&self, data: &Value) {
+    if let Some(log) = &self.log {
+      log.command(self.model.get_time(), &inline_snapshot(&self.data_dir, data.clone()));
+    }
+  }
+  pub fn is_replaying(&self) -> bool { self.replay.is_some() }
+  // Readies the server for the next job after one panicked part way.
+  pub fn recover(&mut self) {
+    self.muted = false;
+  }
+  pub fn get_time(&self) -> f64 { self.model.get_time() }
+  pub fn get_output(&self) -> broadcast::Sender<Arc<Outgoing>> { self.output.clone() }
+  pub fn get_entities(&self) -> Vec<Value> {
+    self.model.entities.values().map(entity_json).collect()
+  }
+  pub fn get_entity(&self, id: i32) -> Option<Value> {
+    self.model.entities.get(&id).map(entity_json)
+  }
+  pub fn get_trips(&self) -> Vec<Value> {
+    self.model.get_trips()
+  }
+  pub fn find_path(&self, from: Vector3, to: Vector3, search: &str) -> Option<Value> {
+    let path = self.model.find_path(from, to, search)?;
+    Some(json!({
+      "search": search,
+      "length": path_length(&path),
+      "path": path.iter().map(|p| [p.x, p.y, p.z]).collect::<Vec<[f64; 3]>>()
+    }))
+  }
+  fn elapsed(&mut self) -> f64 {
+    let diff = SystemTime::now().duration_since(self.start).unwrap();
+    let delta = diff.as_secs_f64() - self.total_time;
+    self.total_time += delta;
+    delta
+  }
+  fn run_command(&mut self, data: &Value) -> Option<Value> {
+    let _span = info_span!("command", command = data["command"].as_str().unwrap_or_default(), time = self.model.get_time()).entered();
     if let Value::String(cmd) = &data["command"] {
       match cmd.as_str() {
-        "CreateEntity" => if let Some(entity) = self.model.create_entity(data) {
-          self.send_entity("AddEntity", &entity);
+        "CreateEntity" => if let Some(entity) = self.model.create_entity(data.clone()) {
+          let details = entity_json(&entity);
+          self.send_event_to_view("AddEntity", &details);
+          self.delta.add(entity.get_id(), &entity);
           self.model.entities.insert(entity.get_id(), entity);
+          return Some(details);
         },
-        "ScheduleTrip" => if let Some(data) = self.model.schedule_trip(&data) {
+        "RemoveEntity" => if let Some(entity) = data["id"].as_i64().and_then(|id| self.model.remove_entity(id as i32)) {
+          self.remove_entity(entity.get_id());
+          return Some(json!({ "id": entity.get_id() }));
+        },
+        "ScheduleTrip" => if let Some(mut data) = self.model.schedule_trip(data) {
           self.model.update(0.);
-          self.send_event_to_view("TripScheduled", &data)
-        }
+          data["etas"] = json!(data["trip_ids"].as_array().unwrap().iter()
+            .filter_map(|id| self.model.get_trip_eta(id.as_i64()? as i32))
+            .collect::<Vec<Value>>());
+          self.send_event_to_view("TripScheduled", &data);
+          return Some(data);
+        },
+        "SetSeed" => if let Some(seed) = data["seed"].as_u64() {
+          self.model.set_seed(seed);
+          self.send_event_to_view("SeedChanged", &json!({ "seed": seed }))
+        },
+        "SetWorldBounds" => if let Some(data) = self.model.set_world_bounds(data) {
+          self.send_event_to_view("WorldBoundsChanged", &data)
+        },
+        // from a script or a replay there is no one to answer
+        "SaveSnapshot" | "LoadSnapshot" | "ExportMetrics" => if let Err(e) = self.answer(data) {
+          error!("{} failed: {}", cmd, e);
+        },
+        "CancelTrip" => if let Some(data) = self.model.cancel_trip(data) {
+          self.send_event_to_view("TripCancelled", &data)
+        },
+        "ReassignTrip" => if let Some(data) = self.model.reassign_trip(data) {
+          self.send_event_to_view("TripReassigned", &data)
+        },
+        "runScript" => {
+          if data["init"] == true {
+            self.reset();
+          }
+          self.script = data["script"].as_array().cloned().unwrap_or_default().into();
+          self.script_resume = self.model.get_time();
+          let commands = self.script.len();
+          self.run_script();
+          return Some(json!({ "commands": commands }));
+        },
+        "kill" => if data["mode"] == "stop" {
+          self.halted = true;
+          self.script.clear();
+          self.send_event_to_view("SimulationStopped", &json!({ "time": self.model.get_time() }));
+        } else {
+          self.reset();
+        },
+        "Update" if self.halted => (),
         "Update" => {
-          let diff = SystemTime::now().duration_since(self.start).unwrap();
-          let delta = diff.as_secs_f64() - self.total_time;
-          self.total_time += delta;
-          let sim_speed = data["simSpeed"].as_f64().unwrap();
-          let dt = delta * sim_speed;
+          let Some(dt) = update_step(data["dt"].as_f64().unwrap_or(0.)) else {
+            warn!(dt = %data["dt"], "update ignored, dt must be a finite positive number");
+            return None;
+          };
+          let started = Instant::now();
           if dt > 0.1 {
             let mut f = 0.;
             while f < dt {
@@ -52,45 +251,707 @@ impl TransitServer {
               f += 0.01;
             }
           } else { self.model.update(dt); }
-          for (_, entity) in self.model.entities.iter() {
-            self.send_entity("UpdateEntity", &entity);
+          self.record_tick(started.elapsed().as_secs_f64());
+          self.run_script();
+          if !self.broadcast_due() { return None; }
+          let seen = self.delta.get_positions();
+          let changes = self.delta.encode(&self.model.entities);
+          if !changes.is_empty() {
+            self.send_world_update(changes, &seen);
+          }
+          let etas = self.model.get_trip_etas();
+          if !etas.is_empty() {
+            self.send_event_to_view("TripEtas", &json!(etas));
           }
         },
         _ => ()
       }
     }
+    None
   }
-  pub fn send_entity(&self, event: &str, entity: &Entity) {
-    let pos = entity.get_position();
-    let dir = entity.get_direction();
-    let col = entity.get_color();
-    self.send_event_to_view(event, &json!({
-      "id": entity.get_id(),
-      "pos": [pos.x, pos.y, pos.z],
-      "dir": [dir.x, dir.y, dir.z],
-      "color": match col {
-        Some(c) => Value::String(c),
-        None => Value::Null
+  // Whether this update's poses and etas go out, at most once per broadcast
+  // interval. Changes held back are sent with the next ones.
+  fn broadcast_due(&mut self) -> bool {
+    let now = Instant::now();
+    let due = match (self.broadcast_interval, self.last_broadcast) {
+      (Some(interval), Some(last)) => now.duration_since(last) >= interval,
+      _ => true
+    };
+    if due { self.last_broadcast = Some(now); }
+    due
+  }
+  fn record_tick(&mut self, duration: f64) {
+    let now = Instant::now();
+    self.ticks += 1;
+    self.update_time += duration;
+    self.last_update_time = duration;
+    self.recent_ticks.push_back(now);
+    while self.recent_ticks.front().is_some_and(|t| now.duration_since(*t).as_secs_f64() > TICK_WINDOW) {
+      self.recent_ticks.pop_front();
+    }
+  }
+  fn get_ticks_per_second(&self) -> f64 {
+    let uptime = SystemTime::now().duration_since(self.start).unwrap().as_secs_f64();
+    let window = uptime.min(TICK_WINDOW);
+    let recent = self.recent_ticks.iter()
+      .filter(|t| t.elapsed().as_secs_f64() <= TICK_WINDOW)
+      .count();
+    if window > 0. { recent as f64 / window } else { 0. }
+  }
+  // Prometheus text exposition of the server and simulation state
+  pub fn get_prometheus_metrics(&self) -> String {
+    let mut out = Exposition::new();
+    out.add("transit_entities", "gauge", "Entities in the simulation by type.",
+      &self.model.get_entity_counts().into_iter()
+        .map(|(kind, n)| (format!("{{type=\"{}\"}}", kind), n as f64))
+        .collect::<Vec<(String, f64)>>());
+    out.single("transit_trips_active", "gauge", "Trips that have not finished yet.", self.model.get_active_trips() as f64);
+    out.single("transit_trips_queued", "gauge", "Trips waiting for a carrier.", self.model.get_queued_trips() as f64);
+    out.add("transit_trips_completed_total", "counter", "Finished trips by final state.",
+      &self.model.get_completed_trips().into_iter()
+        .map(|(state, n)| (format!("{{state=\"{:?}\"}}", state).to_lowercase(), n as f64))
+        .collect::<Vec<(String, f64)>>());
+    out.single("transit_sim_time_seconds", "gauge", "Simulated time.", self.model.get_time());
+    out.single("transit_ticks_total", "counter", "Update commands processed.", self.ticks as f64);
+    out.single("transit_ticks_per_second", "gauge", "Update commands per second over the last 10 seconds.", self.get_ticks_per_second());
+    out.add("transit_update_duration_seconds", "summary", "Wall clock time spent advancing the model per update.", &[
+      ("_sum".to_string(), self.update_time),
+      ("_count".to_string(), self.ticks as f64)
+    ]);
+    out.single("transit_last_update_duration_seconds", "gauge", "Wall clock time the latest update took.", self.last_update_time);
+    out.single("transit_clients", "gauge", "Connected clients.", self.output.receiver_count() as f64);
+    out.finish()
+  }
+  // Runs a scene file as a script, as the viewer does when it loads one
+  pub fn run_scene(&mut self, path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let scene: Vec<Value> = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    self.recieve(json!({ "command": "runScript", "script": scene }));
+    Ok(())
+  }
+  // Runs the script's commands up to its next delay, a `Delay` entry holds the
+  // rest back for `seconds` of sim time. Entries are scene file entries, a
+  // command with its params.
+  fn run_script(&mut self) {
+    while self.model.get_time() >= self.script_resume {
+      let entry = match self.script.pop_front() {
+        Some(entry) => entry,
+        None => return
+      };
+      let command = entry["command"].as_str().unwrap_or_default();
+      if command == "Delay" {
+        self.script_resume = self.model.get_time() + entry["params"]["seconds"].as_f64().unwrap_or(0.);
+        continue;
+      }
+      let mut data = entry["params"].clone();
+      if !data.is_object() || VIEWER_COMMANDS.contains(&command) || matches!(command, "runScript" | "kill") { continue; }
+      data["command"] = json!(command);
+      self.run_command(&data);
+      self.flush_events();
+    }
+  }
+  // Clears the world for a fresh start, telling clients to drop what they had
+  fn reset(&mut self) {
+    let old_ids = self.model.entities.keys().copied().collect::<Vec<i32>>();
+    self.model.reset();
+    self.delta.reset(&self.model.entities);
+    self.script.clear();
+    self.script_resume = 0.;
+    self.halted = false;
+    for id in old_ids {
+      self.remove_entity(id);
+    }
+    self.send_event_to_view("SimulationReset", &json!({ "time": self.model.get_time() }));
+  }
+  // Advances `duration` sim seconds without a client driving the updates
+  pub fn run_headless(&mut self, duration: f64) {
+    let mut elapsed = 0.;
+    while elapsed < duration {
+      let dt = (duration - elapsed).min(1.);
+      self.dispatch(json!({ "command": "Update", "dt": dt }));
+      elapsed += dt;
+    }
+  }
+  pub fn write_metrics(&self, path: &str) -> Result<Vec<String>, String> {
+    self.model.write_metrics(path)
+  }
+  pub fn write_snapshot(&self, path: &str) -> Result<(), String> {
+    fs::write(path, self.model.save_snapshot().to_string()).map_err(|e| format!("{}: {}", path, e))
+  }
+  // metrics asked for by a client, written inside the data directory
+  fn export_metrics(&self, path: &str) -> Result<Vec<String>, String> {
+    let path = data_path(&self.data_dir, path)?;
+    create_parent(&path)?;
+    self.write_metrics(&path.to_string_lossy())
+  }
+  // a snapshot asked for by a client, written inside the data directory
+  fn save_snapshot_file(&self, path: &str) -> Result<(), String> {
+    let path = data_path(&self.data_dir, path)?;
+    create_parent(&path)?;
+    self.write_snapshot(&path.to_string_lossy())
+  }
+  // Tells the clients the server is going away and closes the event log, the
+  // shutdown event being the last thing in it. Nothing runs after this.
+  pub fn shutdown(&mut self) {
+    if self.stopped { return; }
+    self.send_event_to_view("ServerShutdown", &json!({ "time": self.model.get_time() }));
+    if let Some(Err(e)) = self.log.take().map(EventLog::close) {
+      error!("could not close event log: {}", e);
+    }
+    self.stopped = true;
+  }
+  pub fn record(&mut self, path: &str) -> Result<(), String> {
+    self.delta.reset(&self.model.entities);
+    self.log = Some(EventLog::create(path, self.model.get_time(), self.model.save_snapshot())?);
+    Ok(())
+  }
+  pub fn replay(&mut self, path: &str) -> Result<(), String> {
+    let replay = Replay::load(path)?;
+    self.load_snapshot(replay.get_snapshot())?;
+    self.replay = Some(replay);
+    Ok(())
+  }
+  // While replaying, client updates only move the playback clock and the
+  // recorded commands up to it are run in place of the client's own.
+  fn replay_message(&mut self, data: &Value) {
+    match data["command"].as_str() {
+      Some("Update") => {
+        let delta = self.elapsed();
+        let dt = data["dt"].as_f64().unwrap_or(delta * data["simSpeed"].as_f64().unwrap_or(1.));
+        let Some(dt) = update_step(dt) else { return };
+        if let Some(replay) = self.replay.as_mut() {
+          replay.clock = replay.get_end().min(replay.clock + dt);
+        }
+        self.play_until_clock();
+        if let Some(replay) = &self.replay {
+          self.send_event_to_view("ReplayInfo", &replay.to_json())
+        }
       },
-      "details": entity.get_details()
-    }))
+      Some("ReplaySeek") => if let Some(time) = data["time"].as_f64() {
+        self.seek_replay(time)
+      },
+      Some("ReplayInfo") => if let Some(replay) = &self.replay {
+        self.send_event_to_view("ReplayInfo", &replay.to_json())
+      },
+      _ => ()
+    }
+  }
+  fn answer(&mut self, data: &Value) -> Result<Option<(&'static str, Value)>, String> {
+    match data["command"].as_str() {
+      Some("SaveSnapshot") => match data["path"].as_str() {
+        Some(path) => {
+          self.save_snapshot_file(path)?;
+          Ok(Some(("SnapshotSaved", json!({ "path": path, "time": self.model.get_time() }))))
+        },
+        None => Ok(Some(("Snapshot", self.model.save_snapshot())))
+      },
+      Some("LoadSnapshot") => {
+        match data["path"].as_str() {
+          Some(path) => self.load_snapshot_file(&data_path(&self.data_dir, path)?.to_string_lossy())?,
+          None => self.load_snapshot(&data["snapshot"])?
+        }
+        Ok(None)
+      },
+      Some("ExportMetrics") => match data["path"].as_str() {
+        Some(path) => {
+          let files = self.export_metrics(path)?;
+          Ok(Some(("MetricsExported", json!({ "files": files, "time": self.model.get_time() }))))
+        },
+        None if data["format"] == "csv" => Ok(Some(("Metrics", json!(self.model.get_metrics_csv()
+          .into_iter()
+          .map(|(table, text)| (table.to_string(), Value::String(text)))
+          .collect::<serde_json::Map<String, Value>>())))),
+        None => Ok(Some(("Metrics", self.model.get_metrics())))
+      },
+      _ => Err(format!("{} is not a request", data["command"]))
+    }
+  }
+  fn play_until_clock(&mut self) {
+    while let Some(command) = self.replay.as_mut().and_then(|r| r.next_command(r.clock)) {
+      if !matches!(command["command"].as_str(), Some("SaveSnapshot" | "ExportMetrics")) {
+        self.run_command(&command);
+        self.flush_events();
+      }
+    }
+  }
+  // Jumps to `time` without sending the intermediate output, seeking
+  // backwards restarts from the recorded snapshot.
+  fn seek_replay(&mut self, time: f64) {
+    let old_ids = self.model.entities.keys().copied().collect::<Vec<i32>>();
+    self.muted = true;
+    if let Some(replay) = self.replay.as_mut() {
+      if time < replay.clock {
+        replay.rewind();
+        let snapshot = replay.get_snapshot().clone();
+        let _ = self.load_snapshot(&snapshot);
+      }
+    }
+    if let Some(replay) = self.replay.as_mut() {
+      replay.clock = time.clamp(replay.clock, replay.get_end());
+    }
+    self.play_until_clock();
+    self.flush_events();
+    self.muted = false;
+    for id in old_ids {
+      self.remove_entity(id);
+    }
+    for (_, entity) in self.model.entities.iter() {
+      self.send_entity("AddEntity", entity);
+    }
+    self.delta.reset(&self.model.entities);
+    if let Some(replay) = &self.replay {
+      self.send_event_to_view("ReplayInfo", &replay.to_json());
+    }
+  }
+  pub fn load_snapshot_file(&mut self, path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let snapshot: Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    self.load_snapshot(&snapshot)
+  }
+  // A loaded snapshot runs again even if the simulation had been stopped, so
+  // a replay can be scrubbed back past a stop.
+  pub fn load_snapshot(&mut self, snapshot: &Value) -> Result<(), String> {
+    let old_ids = self.model.entities.keys().copied().collect::<Vec<i32>>();
+    self.model.load_snapshot(snapshot).ok_or("invalid snapshot")?;
+    self.delta.reset(&self.model.entities);
+    self.halted = false;
+    for id in old_ids {
+      self.remove_entity(id);
+    }
+    for (_, entity) in self.model.entities.iter() {
+      self.send_entity("AddEntity", entity);
+    }
+    self.send_event_to_view("SnapshotLoaded", &json!({ "time": self.model.get_time() }));
+    Ok(())
+  }
+  fn flush_events(&mut self) {
+    for (event, details) in self.model.take_events() {
+      self.send_event_to_view(&event, &details);
+    }
+  }
+  pub fn send_entity(&self, event: &str, entity: &Entity) {
+    self.send_event_to_view(event, &entity_json(entity))
   }
   pub fn remove_entity(&self, id: i32) {
     self.send_event_to_view("RemoveEntity", &json!({
       "id": id
     }))
   }
+  // Publishes an event to the client tasks
   pub fn send_event_to_view(&self, event: &str, details: &Value) {
-    self.send_message(&json!({
-      "event": event,
-      "details": details
-    }).to_string());
-  }
-  pub fn send_message(&self, message: &str) {
-    for (_, client) in self.clients.iter() {
-      if let Some(sink) = &client.sender {
-        let _ = sink.send(Ok(warp::ws::Message::text(message)));
+    self.publish(event, details, HashMap::new());
+  }
+  // Publishes the changes since the last WorldUpdate, with what clients
+  // filtering by type and area need to know of their entities. `seen` is
+  // where each entity was before.
+  fn send_world_update(&self, changes: Vec<Value>, seen: &HashMap<i32, Vector3>) {
+    let entities = changes.iter()
+      .filter_map(|c| {
+        let id = c["id"].as_i64()? as i32;
+        let entity = self.model.entities.get(&id)?;
+        Some((id, Sighting {
+          kind: entity.get_details()["type"].as_str().unwrap_or_default().to_string(),
+          pos: entity.get_position(),
+          seen: seen.get(&id).copied(),
+          state: full_change(id, entity)
+        }))
+      })
+      .collect();
+    self.publish("WorldUpdate", &json!({ "time": self.model.get_time(), "entities": changes }), entities);
+  }
+  fn publish(&self, event: &str, details: &Value, entities: HashMap<i32, Sighting>) {
+    if self.muted { return; }
+    if let Some(log) = &self.log {
+      log.event(self.model.get_time(), event, details);
+    }
+    let _ = self.output.send(Arc::new(Outgoing::new(event, details, entities)));
+  }
+}
+
+// Commands loading a snapshot from disk are logged with the snapshot itself so
+// a replay does not depend on the file still being there.
+fn inline_snapshot(dir: &Path, mut data: Value) -> Value {
+  if data["command"] != "LoadSnapshot" { return data; }
+  let snapshot = data["path"].as_str()
+    .and_then(|path| data_path(dir, path).ok())
+    .and_then(|path| fs::read_to_string(path).ok())
+    .and_then(|text| serde_json::from_str::<Value>(&text).ok());
+  if let Some(snapshot) = snapshot {
+    data["snapshot"] = snapshot;
+    data.as_object_mut().unwrap().remove("path");
+  }
+  data
+}
+
+// Where a file named by a client lives. Clients only reach files inside the
+// data directory, so the path must be relative and must not climb out of it.
+fn data_path(dir: &Path, path: &str) -> Result<PathBuf, String> {
+  let relative = Path::new(path);
+  let inside = relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+  if path.is_empty() || !inside {
+    return Err(format!("{}: not a relative path inside the data directory", path));
+  }
+  Ok(dir.join(relative))
+}
+
+fn create_parent(path: &Path) -> Result<(), String> {
+  match path.parent() {
+    Some(parent) => fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e)),
+    None => Ok(())
+  }
+}
+
+// the step an Update asks for, None when it is not a length of time
+fn update_step(dt: f64) -> Option<f64> {
+  if dt.is_finite() && dt >= 0. { Some(dt.min(MAX_STEP)) } else { None }
+}
+
+fn entity_json(entity: &Entity) -> Value {
+  let pos = entity.get_position();
+  let dir = entity.get_direction();
+  let col = entity.get_color();
+  json!({
+    "id": entity.get_id(),
+    "pos": [pos.x, pos.y, pos.z],
+    "dir": [dir.x, dir.y, dir.z],
+    "color": match col {
+      Some(c) => Value::String(c),
+      None => Value::Null
+    },
+    "details": entity.get_details()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::encoding::Encoding;
+  use crate::subscription::Subscription;
+
+  fn robot(name: &str, position: [f64; 3]) -> Value {
+    json!({ "command": "CreateEntity", "params": {
+      "type": "robot", "name": name, "mesh": "assets/model/robot.glb",
+      "position": position, "scale": [0.25, 0.25, 0.25], "rotation": [0, 0, 0, 0],
+      "direction": [1, 0, 0], "speed": 30.0, "radius": 1.0, "start": 2.0, "duration": 2.0
+    }})
+  }
+
+  fn trip(name: &str, start: [f64; 3], end: [f64; 3], mode: &str) -> Value {
+    json!({ "command": "ScheduleTrip", "params": {
+      "name": name, "start": start, "end": end, "search": "astar", "mode": mode
+    }})
+  }
+
+  // what the server has published since the last call, as a client sees it
+  fn events(output: &mut broadcast::Receiver<Arc<Outgoing>>) -> Vec<Value> {
+    let mut events = vec![];
+    while let Ok(outgoing) = output.try_recv() {
+      if let Some(frame) = outgoing.frame(Encoding::Json, &Subscription::default()) {
+        events.push(serde_json::from_str(frame.to_str().unwrap()).unwrap());
       }
     }
+    events
+  }
+
+  // a scene entry as a client sends it
+  fn command(entry: Value) -> Value {
+    let mut data = entry["params"].clone();
+    data["command"] = entry["command"].clone();
+    data
+  }
+
+  // the umn scene with a few robots asking for rides, run without clients
+  fn run(seed: u64) -> (String, String) {
+    let mut config = Config { seed: Some(seed), ..Config::default() };
+    assert_eq!(config.validate(), Vec::<String>::new());
+    let mut server = TransitServer::new(&config);
+    server.run_scene("web/scenes/umn.json").unwrap();
+    server.recieve(json!({ "command": "runScript", "script": [
+      robot("Alice", [-300., 264., 90.]),
+      robot("Bob", [700., 264., -400.]),
+      robot("Carol", [100., 264., 300.]),
+      trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly"),
+      trip("Bob", [700., 264., -400.], [-500., 264., 200.], "auto"),
+      { "command": "Delay", "params": { "seconds": 20 } },
+      trip("Carol", [100., 264., 300.], [200., 264., -100.], "drive")
+    ]}));
+    server.run_headless(400.);
+    (server.model.save_snapshot().to_string(), server.model.get_metrics().to_string())
+  }
+
+  #[test]
+  fn seeded_runs_are_identical() {
+    let (snapshot, metrics) = run(7);
+    let trips = serde_json::from_str::<Value>(&metrics).unwrap()["trips"].clone();
+    assert_eq!(trips.as_array().map(|t| t.iter().filter(|t| t["state"] == "Delivered").count()), Some(3));
+    assert_eq!(run(7), (snapshot, metrics));
+  }
+
+  #[test]
+  fn client_paths_stay_in_the_data_directory() {
+    let dir = Path::new("data");
+    assert_eq!(data_path(dir, "runs/a.json"), Ok(PathBuf::from("data/runs/a.json")));
+    assert_eq!(data_path(dir, "./a.json"), Ok(PathBuf::from("data/./a.json")));
+    for path in ["", "/etc/passwd", "../a.json", "runs/../../a.json"] {
+      assert!(data_path(dir, path).is_err(), "{}", path);
+    }
+  }
+
+  #[test]
+  fn requests_answer_only_the_client_asking() {
+    let mut server = TransitServer::new(&Config::default());
+    let mut output = server.get_output().subscribe();
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    events(&mut output);
+    let (event, snapshot) = server.request(json!({ "command": "SaveSnapshot" })).unwrap().unwrap();
+    assert_eq!((event, snapshot["entities"].as_array().map(Vec::len)), ("Snapshot", Some(1)));
+    for path in ["../a.json", "missing.json"] {
+      assert!(server.request(json!({ "command": "LoadSnapshot", "path": path })).is_err());
+    }
+    assert_eq!(events(&mut output), Vec::<Value>::new());
+    let (event, metrics) = server.request(json!({ "command": "ExportMetrics", "format": "csv" })).unwrap().unwrap();
+    assert_eq!((event, metrics["trips"].is_string()), ("Metrics", true));
+    assert!(server.request(json!({ "command": "ExportMetrics", "path": "/tmp/metrics" })).is_err());
+    assert_eq!(events(&mut output), Vec::<Value>::new());
+    assert_eq!(server.request(json!({ "command": "LoadSnapshot", "snapshot": snapshot })), Ok(None));
+    assert_eq!(events(&mut output).last().unwrap()["event"], "SnapshotLoaded");
+  }
+
+  // moves an entity by hand and sends the WorldUpdate that follows
+  fn move_to(server: &mut TransitServer, id: i32, position: [f64; 3]) {
+    let [x, y, z] = position;
+    server.model.entities.get_mut(&id).unwrap().set_position(Vector3::new(x, y, z));
+    server.execute(json!({ "command": "Update", "dt": 0.01 }));
+  }
+
+  #[test]
+  fn world_updates_leave_out_what_clients_have() {
+    let mut server = TransitServer::new(&Config::default());
+    let mut output = server.get_output().subscribe();
+    let id = server.execute(command(robot("Alice", [0., 264., 0.]))).unwrap()["id"].as_i64().unwrap() as i32;
+    server.execute(json!({ "command": "Update", "dt": 0.01 }));
+    assert_eq!(events(&mut output).iter().map(|e| e["event"].clone()).collect::<Vec<Value>>(), vec!["AddEntity"]);
+    move_to(&mut server, id, [5., 264., 0.]);
+    let update = events(&mut output).pop().unwrap();
+    let fields = update["details"]["entities"][0].as_object().unwrap().keys().cloned().collect::<Vec<String>>();
+    assert_eq!(fields, ["dir", "id", "pos"]);
+    server.load_snapshot(&server.model.save_snapshot()).unwrap();
+    server.execute(json!({ "command": "Update", "dt": 0.01 }));
+    assert!(events(&mut output).iter().all(|e| e["event"] != "WorldUpdate"));
+  }
+
+  #[test]
+  fn entities_entering_an_area_are_sent_in_full() {
+    let mut server = TransitServer::new(&Config::default());
+    let mut output = server.get_output().subscribe();
+    let id = server.execute(command(robot("Alice", [0., 264., 0.]))).unwrap()["id"].as_i64().unwrap() as i32;
+    let area = Subscription::from_json(&json!({ "area": { "min": [100, 0, -50], "max": [200, 500, 50] } })).unwrap();
+    let mut updates = |server: &mut TransitServer, position| {
+      move_to(server, id, position);
+      let mut frames = vec![];
+      while let Ok(outgoing) = output.try_recv() {
+        frames.extend(outgoing.frame(Encoding::Json, &area).map(|f| serde_json::from_str::<Value>(f.to_str().unwrap()).unwrap()));
+      }
+      frames.into_iter().filter(|f| f["event"] == "WorldUpdate").collect::<Vec<Value>>()
+    };
+    assert_eq!(updates(&mut server, [50., 264., 0.]), Vec::<Value>::new());
+    let entered = updates(&mut server, [150., 264., 0.]);
+    assert_eq!(entered[0]["details"]["entities"][0]["details"]["name"], "Alice");
+    assert!(entered[0]["details"]["entities"][0].get("pos").is_some());
+    let moved = updates(&mut server, [160., 264., 0.]);
+    assert!(moved[0]["details"]["entities"][0].get("details").is_none());
+  }
+
+  // the umn scene's drones on the route network, ready for trips
+  fn umn(seed: u64) -> TransitServer {
+    let mut config = Config { seed: Some(seed), ..Config::default() };
+    assert_eq!(config.validate(), Vec::<String>::new());
+    let mut server = TransitServer::new(&config);
+    server.run_scene("web/scenes/umn.json").unwrap();
+    server
+  }
+
+  #[test]
+  fn scheduled_trips_come_with_etas() {
+    let mut server = umn(5);
+    let mut output = server.get_output().subscribe();
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    server.execute(command(robot("Bob", [700., 264., -400.])));
+    let flying = server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly"))).unwrap();
+    let eta = &flying["etas"][0];
+    assert_eq!(eta["trip_id"], flying["trip_ids"][0]);
+    assert!(eta["pickup_eta"].as_f64().unwrap() > 0.);
+    assert!(eta["dropoff_eta"].as_f64().unwrap() > eta["pickup_eta"].as_f64().unwrap());
+    let driving = server.execute(command(trip("Bob", [700., 264., -400.], [-500., 264., 200.], "drive"))).unwrap();
+    assert_eq!(driving["etas"][0]["pickup_eta"], 0.);
+    assert!(driving["etas"][0]["dropoff_eta"].as_f64().unwrap() > 0.);
+    let scheduled = events(&mut output).into_iter()
+      .filter(|e| e["event"] == "TripScheduled")
+      .map(|e| e["details"]["etas"].clone())
+      .collect::<Vec<Value>>();
+    assert_eq!(scheduled, [flying["etas"].clone(), driving["etas"].clone()]);
+  }
+
+  #[test]
+  fn removing_a_passenger_frees_its_carrier() {
+    let mut server = umn(5);
+    let mut output = server.get_output().subscribe();
+    let alice = server.execute(command(robot("Alice", [-300., 264., 90.]))).unwrap()["id"].clone();
+    server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly")));
+    let carrier = server.get_trips()[0]["carrier_id"].as_i64().unwrap() as i32;
+    assert!(!server.model.entities[&carrier].get_availability());
+    events(&mut output);
+    assert_eq!(server.execute(json!({ "command": "RemoveEntity", "id": alice })), Some(json!({ "id": alice })));
+    let seen = events(&mut output);
+    assert!(seen.iter().any(|e| e["event"] == "RemoveEntity" && e["details"]["id"] == alice));
+    let failed = seen.iter().find(|e| e["event"] == "TripStateChanged").unwrap();
+    assert_eq!((&failed["details"]["state"], &failed["details"]["reason"]), (&json!("Failed"), &json!("passenger removed")));
+    assert!(server.get_trips().is_empty() && server.get_entity(alice.as_i64().unwrap() as i32).is_none());
+    assert!(server.model.entities[&carrier].get_availability());
+    assert_eq!(server.execute(json!({ "command": "RemoveEntity", "id": alice })), None);
+  }
+
+  #[test]
+  fn removing_a_carrier_lets_its_passenger_ride_again() {
+    let mut server = umn(5);
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly")));
+    let carrier = server.get_trips()[0]["carrier_id"].clone();
+    let spare = server.execute(json!({
+      "command": "CreateEntity", "type": "drone", "name": "Spare", "mesh": "assets/model/drone.glb",
+      "position": [0, 270, 0], "scale": [0.1, 0.1, 0.1], "direction": [1, 0, 0], "speed": 30.0
+    })).unwrap()["id"].clone();
+    server.execute(json!({ "command": "RemoveEntity", "id": carrier }));
+    assert!(server.get_trips().is_empty());
+    server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly")));
+    assert_eq!(server.get_trips()[0]["carrier_id"], spare);
+  }
+
+  #[test]
+  fn exposes_metrics_for_prometheus() {
+    let mut server = umn(5);
+    let _client = server.get_output().subscribe();
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly")));
+    for _ in 0..3 {
+      server.execute(json!({ "command": "Update", "dt": 0.5 }));
+    }
+    let text = server.get_prometheus_metrics();
+    for line in ["transit_entities{type=\"robot\"} 1", "transit_entities{type=\"drone\"} 1", "transit_trips_active 1",
+      "transit_ticks_total 3", "transit_update_duration_seconds_count 3", "transit_clients 1", "# TYPE transit_ticks_total counter"] {
+      assert!(text.lines().any(|l| l == line), "{} missing from\n{}", line, text);
+    }
+    for sample in text.lines().filter(|l| !l.starts_with('#')) {
+      let (name, value) = sample.rsplit_once(' ').unwrap();
+      assert!(name.starts_with("transit_") && value.parse::<f64>().is_ok(), "{}", sample);
+    }
+  }
+
+  #[test]
+  fn colors_change_by_one_notification() {
+    let mut config = Config { seed: Some(5), ..Config::default() };
+    assert_eq!(config.validate(), Vec::<String>::new());
+    let mut server = TransitServer::new(&config);
+    server.run_scene("web/scenes/umn.json").unwrap();
+    let mut output = server.get_output().subscribe();
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly")));
+    let carrier = server.get_trips()[0]["carrier_id"].clone();
+    let mut seen = events(&mut output);
+    while !server.get_trips().is_empty() && server.get_time() < 300. {
+      server.execute(json!({ "command": "Update", "dt": 0.5 }));
+      seen.extend(events(&mut output));
+    }
+    assert!(seen.iter().filter(|e| e["event"] == "WorldUpdate").all(|e| !e.to_string().contains("\"color\"")));
+    let colors = seen.iter()
+      .filter(|e| e["details"]["value"] == "updateDetails" && e["details"]["id"] == carrier)
+      .map(|e| e["details"]["details"]["color"].clone())
+      .collect::<Vec<Value>>();
+    assert_eq!(colors, [json!("#ffff00"), json!("#00ff00"), Value::Null]);
+  }
+
+  #[test]
+  fn snapshots_run_after_a_stop() {
+    let mut server = TransitServer::new(&Config::default());
+    let snapshot = server.model.save_snapshot();
+    server.recieve(json!({ "command": "kill", "mode": "stop" }));
+    server.recieve(json!({ "command": "Update", "dt": 0.5 }));
+    assert_eq!(server.get_time(), 0.);
+    server.load_snapshot(&snapshot).unwrap();
+    server.recieve(json!({ "command": "Update", "dt": 0.05 }));
+    assert_eq!(server.get_time(), 0.05);
+  }
+
+  #[test]
+  fn cancels_live_trips_by_id() {
+    let mut server = TransitServer::new(&Config::default());
+    server.execute(command(robot("Alice", [-300., 264., 90.])));
+    let scheduled = server.execute(command(trip("Alice", [-300., 264., 90.], [600., 264., -300.], "fly"))).unwrap();
+    let trip_id = scheduled["trip_ids"][0].clone();
+    let mut output = server.get_output().subscribe();
+    server.execute(json!({ "command": "CancelTrip", "name": "Alice" }));
+    server.execute(json!({ "command": "CancelTrip", "trip_id": trip_id }));
+    server.execute(json!({ "command": "CancelTrip", "trip_id": trip_id }));
+    let cancelled = events(&mut output).into_iter().filter(|e| e["event"] == "TripCancelled").collect::<Vec<Value>>();
+    assert_eq!(cancelled.len(), 1);
+    assert_eq!(cancelled[0]["details"]["trip_id"], trip_id);
+  }
+
+  #[test]
+  fn replays_and_seeks_past_a_reset() {
+    let path = std::env::temp_dir().join(format!("transit-replay-{}.jsonl", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    let mut recording = TransitServer::new(&Config { seed: Some(3), ..Config::default() });
+    recording.record(&path).unwrap();
+    recording.execute(command(robot("Alice", [-300., 264., 90.])));
+    for _ in 0..4 {
+      recording.execute(json!({ "command": "Update", "dt": 0.5 }));
+    }
+    recording.execute(json!({ "command": "kill", "mode": "reset" }));
+    recording.execute(json!({ "command": "Update", "dt": 0.5 }));
+    recording.execute(command(robot("Bob", [700., 264., -400.])));
+    recording.execute(command(robot("Carol", [100., 264., 300.])));
+    recording.execute(json!({ "command": "Update", "dt": 0.5 }));
+    recording.shutdown();
+
+    let mut replay = TransitServer::new(&Config::default());
+    replay.replay(&path).unwrap();
+    let mut output = replay.get_output().subscribe();
+    let info = |replay: &TransitServer| replay.replay.as_ref().unwrap().to_json();
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+    // the reset sends sim time back to 0, the replay's timeline carries on
+    assert!(close(info(&replay)["end"].as_f64().unwrap(), 2.5));
+    replay.recieve(json!({ "command": "ReplaySeek", "time": 10. }));
+    assert_eq!(replay.model.entities.len(), 2);
+    assert!(close(replay.get_time(), 1.));
+    replay.recieve(json!({ "command": "ReplaySeek", "time": 0.1 }));
+    assert_eq!(replay.model.entities.len(), 1);
+    assert_eq!(info(&replay)["time"], json!(0.1));
+    assert!(close(replay.get_time(), 0.5));
+    replay.recieve(json!({ "command": "ReplaySeek", "time": 2.2 }));
+    assert_eq!(replay.model.entities.len(), 0);
+    assert!(close(replay.get_time(), 0.5));
+    // the clients hear about every seek
+    let seen = events(&mut output).into_iter().filter(|e| e["event"] == "ReplayInfo").count();
+    assert_eq!(seen, 3);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn update_steps_are_bounded() {
+    assert_eq!(update_step(0.02), Some(0.02));
+    assert_eq!(update_step(1e12), Some(MAX_STEP));
+    assert_eq!(update_step(-1.), None);
+    assert_eq!(update_step(f64::NAN), None);
+    assert_eq!(update_step(f64::INFINITY), None);
+  }
+
+  #[test]
+  fn rejects_unusable_updates() {
+    let mut server = TransitServer::new(&Config::default());
+    for dt in [json!(-5.), json!(1e12)] {
+      server.recieve(json!({ "command": "Update", "dt": dt }));
+    }
+    assert!((server.get_time() - MAX_STEP).abs() < 1e-9, "{}", server.get_time());
   }
 }
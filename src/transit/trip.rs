@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::math::vector3::Vector3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TripState {
+  Requested,
+  Assigned,
+  EnRouteToPickup,
+  PickedUp,
+  InTransit,
+  Delivered,
+  Cancelled,
+  Failed
+}
+impl TripState {
+  pub fn is_terminal(&self) -> bool {
+    matches!(self, TripState::Delivered | TripState::Cancelled | TripState::Failed)
+  }
+  pub fn is_picked_up(&self) -> bool {
+    matches!(self, TripState::PickedUp | TripState::InTransit)
+  }
+  // the lifecycle a trip moves through, any live trip can also be cancelled
+  // or fail, and a trip waiting for its drone can go back to Assigned when
+  // it is handed to another one
+  pub fn can_become(&self, next: TripState) -> bool {
+    use TripState::*;
+    match (self, next) {
+      (state, Cancelled | Failed) => !state.is_terminal(),
+      (Requested, Assigned) => true,
+      (Assigned, EnRouteToPickup | InTransit) => true,
+      (EnRouteToPickup, Assigned | PickedUp) => true,
+      (PickedUp, InTransit) => true,
+      (InTransit, Delivered) => true,
+      _ => false
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TripMode {
+  Fly,
+  Drive,
+  Mixed
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trip {
+  pub id: i32,
+  pub name: String,
+  pub carrier_id: Option<i32>,
+  pub passenger_id: i32,
+  pub state: TripState,
+  pub history: Vec<(TripState, f64)>,
+  pub current_destination: Vector3,
+  pub mode: TripMode,
+  #[serde(default)]
+  pub strategy: String,
+  pub pickup: Option<Vector3>,
+  pub last_leg: Vec<Vector3>,
+  pub driving: bool
+}
+
+impl Trip {
+  pub fn new(id: i32, name: String, passenger_id: i32, destination: Vector3, time: f64) -> Self {
+    Trip {
+      id, name, passenger_id,
+      carrier_id: None,
+      state: TripState::Requested,
+      history: vec![(TripState::Requested, time)],
+      current_destination: destination,
+      mode: TripMode::Fly,
+      strategy: String::new(),
+      pickup: None,
+      last_leg: vec![],
+      driving: false
+    }
+  }
+  // moves the trip to `state`, None and no change when the lifecycle does not
+  // allow it
+  pub fn transition(&mut self, state: TripState, time: f64) -> Option<Value> {
+    if !self.state.can_become(state) { return None; }
+    let previous = self.state;
+    self.state = state;
+    self.history.push((state, time));
+    let mut details = self.to_json();
+    details["previous"] = json!(previous);
+    Some(details)
+  }
+  pub fn to_json(&self) -> Value {
+    json!({
+      "trip_id": self.id,
+      "name": self.name,
+      "carrier_id": self.carrier_id,
+      "passenger_id": self.passenger_id,
+      "state": self.state,
+      "mode": self.mode,
+      "time": self.history.last().map(|(_, t)| *t),
+      "history": self.history.iter()
+        .map(|(s, t)| json!({ "state": s, "time": t }))
+        .collect::<Vec<Value>>()
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use TripState::*;
+
+  const STATES: [TripState; 8] = [Requested, Assigned, EnRouteToPickup, PickedUp, InTransit, Delivered, Cancelled, Failed];
+
+  fn trip_in(state: TripState) -> Trip {
+    let mut trip = Trip::new(1, "robot".to_string(), 2, Vector3::new(0., 0., 0.), 0.);
+    trip.state = state;
+    trip
+  }
+
+  #[test]
+  fn allows_the_lifecycle() {
+    let allowed = [
+      (Requested, Assigned),
+      (Assigned, EnRouteToPickup),
+      (Assigned, InTransit),
+      (EnRouteToPickup, Assigned),
+      (EnRouteToPickup, PickedUp),
+      (PickedUp, InTransit),
+      (InTransit, Delivered)
+    ];
+    for from in STATES {
+      for to in STATES {
+        let expected = allowed.contains(&(from, to)) || (!from.is_terminal() && matches!(to, Cancelled | Failed));
+        assert_eq!(from.can_become(to), expected, "{:?} -> {:?}", from, to);
+      }
+    }
+  }
+
+  #[test]
+  fn terminal_states_are_final() {
+    for from in [Delivered, Cancelled, Failed] {
+      assert!(STATES.iter().all(|to| !from.can_become(*to)), "{:?}", from);
+    }
+  }
+
+  #[test]
+  fn transition_records_history() {
+    let mut trip = trip_in(Requested);
+    let details = trip.transition(Assigned, 1.5).unwrap();
+    assert_eq!(trip.state, Assigned);
+    assert_eq!(trip.history, vec![(Requested, 0.), (Assigned, 1.5)]);
+    assert_eq!(details["previous"], json!(Requested));
+    assert_eq!(details["state"], json!(Assigned));
+  }
+
+  #[test]
+  fn illegal_transition_leaves_trip_unchanged() {
+    let mut trip = trip_in(Delivered);
+    assert!(trip.transition(EnRouteToPickup, 2.).is_none());
+    let mut trip2 = trip_in(Cancelled);
+    assert!(trip2.transition(InTransit, 2.).is_none());
+    assert_eq!(trip.state, Delivered);
+    assert_eq!(trip.history.len(), 1);
+  }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::graph::graph::Graph;
+use crate::math::vector3::Vector3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldBounds {
+  pub min: Vector3,
+  pub max: Vector3
+}
+impl WorldBounds {
+  pub fn new(min: Vector3, max: Vector3) -> Self {
+    WorldBounds { min, max }
+  }
+  pub fn from_graph(graph: &Graph) -> Option<Self> {
+    graph.bounding_box().map(|(min, max)| WorldBounds { min, max })
+  }
+  pub fn from_json(data: &Value) -> Option<Self> {
+    let min = data["min"].as_array()?.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>()?;
+    let max = data["max"].as_array()?.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>()?;
+    if min.len() != 3 || max.len() != 3 { return None; }
+    Some(WorldBounds { min: Vector3::from_vec(&min), max: Vector3::from_vec(&max) })
+  }
+  pub fn to_json(&self) -> Value {
+    json!({
+      "min": [self.min.x, self.min.y, self.min.z],
+      "max": [self.max.x, self.max.y, self.max.z]
+    })
+  }
+  pub fn contains(&self, p: Vector3) -> bool {
+    (0..3).all(|i| self.min[i] <= p[i] && p[i] <= self.max[i])
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SampleArea {
+  Bounds,
+  Graph,
+  Region(String)
+}
+impl SampleArea {
+  // "bounds" and "graph" name the built in areas, anything else is a region
+  pub fn from_json(data: &Value) -> Option<Self> {
+    match data.as_str()? {
+      "bounds" => Some(SampleArea::Bounds),
+      "graph" => Some(SampleArea::Graph),
+      region => Some(SampleArea::Region(region.to_string()))
+    }
+  }
+}
+
+pub struct World {
+  seed: u64,
+  rng: ChaCha12Rng,
+  bounds: WorldBounds,
+  regions: BTreeMap<String, WorldBounds>
+}
+
+impl World {
+  pub fn new(seed: u64) -> Self {
+    World {
+      seed,
+      rng: ChaCha12Rng::seed_from_u64(seed),
+      bounds: WorldBounds::new(Vector3::origin(), Vector3::origin()),
+      regions: BTreeMap::new()
+    }
+  }
+  pub fn get_seed(&self) -> u64 { self.seed }
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+    self.rng = ChaCha12Rng::seed_from_u64(seed);
+  }
+  pub fn get_bounds(&self) -> WorldBounds { self.bounds }
+  pub fn set_bounds(&mut self, bounds: WorldBounds) { self.bounds = bounds; }
+  pub fn set_region(&mut self, name: String, bounds: WorldBounds) {
+    self.regions.insert(name, bounds);
+  }
+  pub fn get_region(&self, name: &str) -> Option<WorldBounds> {
+    self.regions.get(name).copied()
+  }
+  pub fn save(&self) -> Value {
+    json!({
+      "seed": self.seed,
+      "word_pos": self.rng.get_word_pos().to_string(),
+      "bounds": self.bounds,
+      "regions": self.regions
+    })
+  }
+  pub fn restore(&mut self, data: &Value) -> Option<()> {
+    self.set_seed(data["seed"].as_u64()?);
+    self.rng.set_word_pos(data["word_pos"].as_str()?.parse::<u128>().ok()?);
+    self.bounds = serde_json::from_value(data["bounds"].clone()).ok()?;
+    self.regions = serde_json::from_value(data["regions"].clone()).ok()?;
+    Some(())
+  }
+  pub fn to_json(&self) -> Value {
+    json!({
+      "seed": self.seed,
+      "bounds": self.bounds.to_json(),
+      "regions": self.regions.iter()
+        .map(|(k, v)| (k.clone(), v.to_json()))
+        .collect::<serde_json::Map<String, Value>>()
+    })
+  }
+  fn sample_box(&mut self, bounds: WorldBounds, height: f64) -> Vector3 {
+    let x = bounds.min.x + self.rng.gen::<f64>()*(bounds.max.x - bounds.min.x);
+    let z = bounds.min.z + self.rng.gen::<f64>()*(bounds.max.z - bounds.min.z);
+    Vector3::new(x, height, z)
+  }
+  // Picks a random point in `area`, keeping `height` for points that are not
+  // taken from the graph. Without a graph or a known region it falls back to
+  // the world bounds.
+  pub fn sample(&mut self, area: &SampleArea, graph: Option<&Graph>, height: f64) -> Vector3 {
+    match (area, graph) {
+      (SampleArea::Graph, Some(graph)) => {
+        let nodes = (0..graph.nodes.len())
+          .filter(|i| !graph.adjacency_list[*i].is_empty())
+          .collect::<Vec<usize>>();
+        if nodes.is_empty() { return self.sample_box(self.bounds, height); }
+        let i = nodes[self.rng.gen_range(0..nodes.len())];
+        graph.nodes[i].get_position()
+      },
+      (SampleArea::Region(name), _) => {
+        let bounds = self.get_region(name).unwrap_or(self.bounds);
+        self.sample_box(bounds, height)
+      },
+      _ => self.sample_box(self.bounds, height)
+    }
+  }
+}
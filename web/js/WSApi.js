@@ -1,7 +1,13 @@
-function WSApi(host = null) {
+// encoding may be "msgpack" or "cbor" to receive pose updates as binary,
+// the server falls back to json when it does not know it. A session name
+// joins that simulation instead of the shared default one.
+function WSApi(host = null, encoding = null, session = null) {
     var self = this;
     var hostname = host != null ? host : location.hostname+(location.port ? ':'+location.port: '');
-    this.socket = new WebSocket("ws://" + hostname, "web_server");
+    var protocols = encoding ? ["web_server." + encoding, "web_server"] : "web_server";
+    var path = session ? "/ws/" + encodeURIComponent(session) : "";
+    this.socket = new WebSocket("ws://" + hostname + path, protocols);
+    this.socket.binaryType = "arraybuffer";
     this.callbacks = {};
     this.requestId = 0;
     this.id = null;
@@ -9,13 +15,14 @@ function WSApi(host = null) {
     this.onmessage = null;
 
     this.socket.onmessage = function (msg) {
-        var data = JSON.parse(msg.data);
+        var data = msg.data instanceof ArrayBuffer ? self.decode(msg.data) : JSON.parse(msg.data);
 
-        if (typeof(data) == 'number') {
-            self.id = +msg.data;
+        // the server greets every client with its id before anything else
+        if (data.event == "Welcome") {
+            self.id = data.details.client_id;
+            self.server = data.details;
             self.connected = true;
-            //console.log(self.id);
-            return;
+            self.onwelcome(data.details);
         }
 
         if ("id" in data && data.id in self.callbacks) {
@@ -43,11 +50,22 @@ function WSApi(host = null) {
 
     this.connected = false;
 
-    this.connect.then(function() {
-        //self.connected = true;
+    this.welcome = new Promise(function(resolve, reject) {
+        self.onwelcome = resolve;
     });
 }
 
+WSApi.prototype.decode = function(buffer) {
+    var bytes = new Uint8Array(buffer);
+    if (this.socket.protocol == "web_server.msgpack") {
+        return MessagePack.decode(bytes);
+    }
+    if (this.socket.protocol == "web_server.cbor") {
+        return CBOR.decode(bytes);
+    }
+    return JSON.parse(new TextDecoder().decode(bytes));
+}
+
 WSApi.prototype.sendPostCommand = function(cmd, data, calcVal) {
     console.log(this.id);
     return this.sendCommand(cmd, data, calcVal, true);
@@ -91,9 +109,8 @@ WSApi.prototype.sendCommand = function(cmd, data, calcVal, isPost = false) {
     }
     else {
         return new Promise(function(resolve, reject) {
-            self.connect.then(function() {
-                self.connected = true;
-                    self.sendCommand(cmd, data, calcVal).then(
+            self.welcome.then(function() {
+                    self.sendCommand(cmd, data, calcVal, isPost).then(
                         function(data) {
                             resolve(data);
                         });
@@ -5,11 +5,16 @@ import { OrbitControls } from 'https://unpkg.com/three@0.152.0/examples/jsm/cont
 import { GLTFLoader } from 'https://unpkg.com/three@0.152.0/examples/jsm/loaders/GLTFLoader.js';
 import { OBJLoader } from 'https://unpkg.com/three@0.152.0/examples/jsm/loaders/OBJLoader.js';
 
-let api = new WSApi();
+// ?encoding=msgpack or ?encoding=cbor switches pose updates to binary frames
+let params = new URLSearchParams(location.search);
+let api = new WSApi(null, params.get("encoding"), params.get("session"));
 var connected = false;
 var entities = {};
 var entityList = [];
+// the last color each entity was given, for models still loading when it came
+var entityColors = {};
 var sceneFile = "scenes/umn.json";
+var sceneScript = [];
 var sceneModel = "assets/model/umn.obj";
 var sceneTexture = "assets/texture/umn.png";
 var sceneScale = [0.05,0.05,0.05];
@@ -21,6 +26,7 @@ var paths = {};
 var currentView = -1;
 var showRoutes = false;
 var showPaths = true;
+var removed = {};
 
 // More important related to models and animation.
 var geometry, material, mesh;
@@ -41,6 +47,12 @@ $( document ).ready(function() {
     simSpeed = this.value / 10.0;
   }
 
+  // Scrub through a recorded run, only shown when the server is replaying
+  var replaySeek = document.getElementById("replaySeek");
+  replaySeek.onchange = function() {
+    api.sendCommand("ReplaySeek", { time: parseFloat(this.value) });
+  }
+
   // Init() starts up the scene and its update loop.
   init();
 
@@ -56,61 +68,37 @@ $( document ).ready(function() {
           addEntity(data.details);
         }
         if (data.event == "UpdateEntity") {
-          //console.log(data.details);
-          var e = data.details;
-
-          if (e.id in entities) {
-            var model = entities[e.id];
-            model.position.x = e.pos[0];
-            model.position.y = e.pos[1];
-            model.position.z = e.pos[2];
-            
-            model.position.x = model.position.x/14.2;
-            model.position.y = model.position.y/20.0 - 13.0;
-            model.position.z = model.position.z/14.2;
-
-            model.position.x += model.offset.x;
-            model.position.y += model.offset.y;
-            model.position.z += model.offset.z;
-
-            var dir = new THREE.Vector3(e.dir[0], e.dir[1], e.dir[2]);
-            var pos = new THREE.Vector3();
-            pos.addVectors(dir, model.position);
-
-            var vector = new THREE.Vector3( 0, 1, 0 );
-            vector = model.worldToLocal(vector);
-
-            if(e.color) {
-              model.children[0].traverse((o) => {
-                if(o.isMesh) {
-                  c = o.userData.defaultColor.clone();
-                  c.multiply(new THREE.Color(e.color));
-                  o.material.color.set(c);
-                }
-              });
-            } else {
-              model.children[0].traverse((o) => {
-                if(o.isMesh) {
-                  o.material.color.set(o.userData.defaultColor);
-                }
-              })
-            }
-
-            var adjustedDirVector = model.localToWorld(new THREE.Vector3(0,0,0)).add(dir);
-            model.lookAt(adjustedDirVector);
-          }
-
-          if (currentView >= 0) {
-            controls.target.copy(entities[currentView].position);
-            controls.update();
-          }
+          updateEntity(data.details);
+        }
+        if (data.event == "WorldUpdate") {
+          data.details.entities.forEach(updateEntity);
         }
         if (data.event == "RemoveEntity") {
           //console.log(data);
           removeEntity(data.details.id);
         }  
+        if (data.event == "ReplayInfo") {
+          var replay = data.details;
+          document.getElementById("replay").style.display = "block";
+          document.getElementById("replayTime").innerHTML = replay.time.toFixed(1);
+          replaySeek.min = replay.start;
+          replaySeek.max = replay.end;
+          replaySeek.value = replay.time;
+        }
         if (data.event == "observe") {
-          displayNotification(data.details);
+          traceLeg(data.details);
+        }
+        if (data.event == "Notification") {
+          displayJSON(Object.assign({ command: "notification" }, data.details));
+        }
+        if (data.event == "CommandRejected") {
+          displayNotification({ info: data.details.command + " refused: " + data.details.reason + "\n" });
+        }
+        if (data.event == "CommandFailed") {
+          displayNotification({ info: data.details.command + " failed: " + data.details.reason + "\n" });
+        }
+        if (data.event == "ServerShutdown") {
+          displayNotification({ info: "Server stopped at " + data.details.time.toFixed(1) + "s\n" });
         }
       }
     }
@@ -120,6 +108,7 @@ $( document ).ready(function() {
   }
 
   loadScene(sceneFile);
+  api.sendCommand("ReplayInfo", {});
 });
 
 /*// This function is triggered once the web socket is opened.
@@ -189,9 +178,10 @@ function changeView() {
 }
 
 // This function builds the initial campus/city scene.
-function loadScene(file, initialScene = true) {
+function loadScene(file) {
   sceneFile = file;
   $.getJSON(sceneFile, function(json) {
+    sceneScript = json;
     console.log(json);
     for (var i = 0; i < json.length; i++) {
       var command = json[i];
@@ -206,17 +196,10 @@ function loadScene(file, initialScene = true) {
       if (command.command == "AddMesh") {
         addMesh(command.params);
       }
-      if (command.command == "CreateEntity") {
-        api.sendCommand(command.command, command.params);
-      }
     }
     loadModels();
-    /*if (initialScene) {
-      socket.send(JSON.stringify({command: "runScript", "init":true, "script": json}));
-    }
-    else {
-      socket.send(JSON.stringify({command: "runScript", "script": json}));
-    }*/
+    // the server runs the scene's commands, adding to whatever is running
+    api.sendCommand("runScript", { init: false, script: json });
   });
 }
 var msg = "";
@@ -232,12 +215,20 @@ function displayJSON(data) {
   //data should be a standard JSON-style object
   if (data["command"] == "notification") {
     if (data["value"] == 'updateDetails') {
-      if (data["details"].color != undefined) {
-        entities[data["id"]].children[1].material.color.setHex(parseInt(data["details"].color, 16));
-        entities[data["id"]].children[1].material.opacity = 0.5;
+      entityColors[data["id"]] = data["details"].color;
+      if (entities[data["id"]]) {
+        tintEntity(entities[data["id"]], data["details"].color);
+      }
+      var highlight = entities[data["id"]] ? entities[data["id"]].children[1] : undefined;
+      if (highlight == undefined || highlight.material == undefined) {
+        return;
+      }
+      if (data["details"].color) {
+        highlight.material.color.set(data["details"].color);
+        highlight.material.opacity = 0.5;
       }
       else {
-        entities[data["id"]].children[1].material.opacity = 0.0;
+        highlight.material.opacity = 0.0;
       }
       return;
     }
@@ -250,57 +241,66 @@ function displayJSON(data) {
       'idle': ' stopped moving.'
     }
     string_ending = type_lookup[data["value"]];
-    additional_string = "Entity #" + data["id"] + string_ending + "\r\n";
+    if (data["info"] != undefined) {
+      additional_string = data["info"] + ".\r\n";
+    }
+    else {
+      additional_string = "Entity #" + data["id"] + string_ending + "\r\n";
+    }
     notifbar = document.getElementById("notification-bar");
     notifbar.textContent += additional_string;
+  }
+}
 
-    const entityId = data["id"];
+// This function draws the leg a drone has started, or clears it once the
+// drone stops.
+function traceLeg(data) {
+  const entityId = data["id"];
 
-    if (data["value"] == 'idle' || data["value"] == 'moving') {
-      if (entityId in paths) {
-        scene.remove(paths[entityId]);
-        delete paths[entityId];
-      }
+  if (data["value"] == 'idle' || data["value"] == 'moving') {
+    if (entityId in paths) {
+      scene.remove(paths[entityId]);
+      delete paths[entityId];
     }
-    if (data["value"] == 'idle') {
-      for ( var mixer of mixers ) {
-        if (entityId == mixer.id) {
-          mixer.duration = 0;
-        }
+  }
+  if (data["value"] == 'idle') {
+    for ( var mixer of mixers ) {
+      if (entityId == mixer.id) {
+        mixer.duration = 0;
       }
     }
-    else if (data["value"] == 'moving') {
-      if ("path" in data) {
-        //create a blue LineBasicMaterial
-        var material = new THREE.LineBasicMaterial( { color: 0xf0fc03 } );
-        const points = [];
-        for (var point of  data["path"]) {
-          points.push( new THREE.Vector3( point[0], point[1], point[2] ) );
-        }
-        /*points.push( new THREE.Vector3( - 10, 0, 0 ) );
-        points.push( new THREE.Vector3( 0, 10, 0 ) );
-        points.push( new THREE.Vector3( 10, 0, 0 ) );*/
-        const geometry = new THREE.BufferGeometry().setFromPoints( points );
-
-        const line = new THREE.Line( geometry, material );
-        //console.log(routes);
-        if (routes.length > 0) {
-          line.position.copy( routes[0].position );
-          line.scale.copy( routes[0].scale );
-          line.material.color.setHex(routes[0].material.color.getHex());
-        }
-
-        //"position": [-0.0,-12.5,-0.0],
-        //      "scale": [0.0705,0.05,0.0705],
-        paths[entityId] = line;
-        scene.add( line );
+  }
+  else if (data["value"] == 'moving') {
+    if ("path" in data) {
+      //create a blue LineBasicMaterial
+      var material = new THREE.LineBasicMaterial( { color: 0xf0fc03 } );
+      const points = [];
+      for (var point of  data["path"]) {
+        points.push( new THREE.Vector3( point[0], point[1], point[2] ) );
       }
+      /*points.push( new THREE.Vector3( - 10, 0, 0 ) );
+      points.push( new THREE.Vector3( 0, 10, 0 ) );
+      points.push( new THREE.Vector3( 10, 0, 0 ) );*/
+      const geometry = new THREE.BufferGeometry().setFromPoints( points );
+
+      const line = new THREE.Line( geometry, material );
+      //console.log(routes);
+      if (routes.length > 0) {
+        line.position.copy( routes[0].position );
+        line.scale.copy( routes[0].scale );
+        line.material.color.setHex(routes[0].material.color.getHex());
+      }
+
+      //"position": [-0.0,-12.5,-0.0],
+      //      "scale": [0.0705,0.05,0.0705],
+      paths[entityId] = line;
+      scene.add( line );
+    }
 
-      for ( var mixer of mixers ) {
-        if (entityId == mixer.id) {
+    for ( var mixer of mixers ) {
+      if (entityId == mixer.id) {
 
-          mixer.duration = 2;
-        }
+        mixer.duration = 2;
       }
     }
   }
@@ -427,6 +427,11 @@ function addMesh(obj) {
 // parameter so that they can be individually placed around the scene.
 // Currently, this only works for GLTF/glb formats.
 const onLoad = ( gltf, position, scale, start, duration, details, id ) => {
+  // the entity was removed while its model was still loading
+  if (id in removed) {
+    delete removed[id];
+    return;
+  }
   const model = gltf.scene.children[ 0 ];
   model.scale.copy( scale );
 
@@ -464,6 +469,10 @@ const onLoad = ( gltf, position, scale, start, duration, details, id ) => {
     }
   });
 
+  if (entityColors[id]) {
+    tintEntity(group, entityColors[id]);
+  }
+
   models.push(group);
   scene.add( group );
   entities[id] = group;
@@ -492,25 +501,91 @@ function addEntity(data) {
   loader.load( data.details.mesh, gltf => onLoad( gltf, position, scale, data.details.start, data.details.duration, data.details, id ), onProgress, onError );
 }
 
+// Tints an entity's meshes with its color, or puts them back without one
+function tintEntity(model, color) {
+  model.children[0].traverse((o) => {
+    if(o.isMesh) {
+      if(color) {
+        var c = o.userData.defaultColor.clone();
+        c.multiply(new THREE.Color(color));
+        o.material.color.set(c);
+      } else {
+        o.material.color.set(o.userData.defaultColor);
+      }
+    }
+  });
+}
+
+// Applies an entity update, a WorldUpdate entry only carries the fields that
+// changed so each one is optional.
+function updateEntity(e) {
+  if (e.id in entities) {
+    var model = entities[e.id];
+    if ("pos" in e) {
+      model.position.x = e.pos[0];
+      model.position.y = e.pos[1];
+      model.position.z = e.pos[2];
+
+      model.position.x = model.position.x/14.2;
+      model.position.y = model.position.y/20.0 - 13.0;
+      model.position.z = model.position.z/14.2;
+
+      model.position.x += model.offset.x;
+      model.position.y += model.offset.y;
+      model.position.z += model.offset.z;
+    }
+
+    if ("dir" in e) {
+      var dir = new THREE.Vector3(e.dir[0], e.dir[1], e.dir[2]);
+      var adjustedDirVector = model.localToWorld(new THREE.Vector3(0,0,0)).add(dir);
+      model.lookAt(adjustedDirVector);
+    }
+
+    if ("details" in e) {
+      $("#entitySelect option[value='" + e.id + "']").text(e.details.name);
+    }
+  }
+
+  if (currentView >= 0 && currentView in entities) {
+    controls.target.copy(entities[currentView].position);
+    controls.update();
+  }
+}
+
 function removeEntity(id) {
   console.log(models);
+  $("#entitySelect option[value='" + id + "']").remove();
+  if (!(id in entities)) {
+    removed[id] = true;
+    return;
+  }
   var model = entities[id];
   models = models.filter(function(value, index, arr){ return value != model;});
   scene.remove( model );
-  $("#entitySelect option[value='" + id + "']").remove();
   delete entities[id];
+  entityList = entityList.filter(function(value) { return value != id; });
+  for (var i = mixers.length - 1; i >= 0; i--) {
+    if (mixers[i].id == id) {
+      mixers.splice(i, 1);
+    }
+  }
   if (currentView == id) {
     currentView = -1;
   }
   console.log(models);
 }
 
-/*// This function kills the webpage's socket connection.
-function kill() {
-  if (connected) {
-    socket.send(JSON.stringify({command: "kill"}));
-  }
-}*/
+// This function resets the simulation, or stops it with mode "stop". The
+// server only accepts it with its admin token.
+function kill(token, mode = "reset") {
+  api.sendCommand("kill", { token: token, mode: mode });
+}
+
+// This function starts the simulation over from the current scene, for
+// everyone watching it. It needs the admin token like kill.
+function restartScene(token) {
+  api.sendCommand("runScript", { init: true, token: token, script: sceneScript });
+}
 
 var time = 0.0;
 